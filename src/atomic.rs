@@ -0,0 +1,101 @@
+//crash-safe file writes: data goes to a temporary file in the same directory,
+//is flushed to disk and then renamed over the target, so the target is
+//either the complete old content or the complete new content
+use std::{
+    fs::{remove_file, rename, File},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+//distinguishes temporary files of concurrent writers in the same process
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct AtomicFile {
+    file: File,
+    tmp: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(target: &Path) -> Result<Self, std::io::Error> {
+        let name = target
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let tmp = target.with_file_name(format!(
+            ".{}.tmp-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options().write(true).create_new(true).open(&tmp)?;
+        Ok(AtomicFile {
+            file,
+            tmp,
+            target: target.to_path_buf(),
+            committed: false,
+        })
+    }
+
+    //move the file to a different target when committed, for content whose
+    //name is only known once written; it must be on the same filesystem
+    pub fn retarget(&mut self, target: &Path) {
+        self.target = target.to_path_buf();
+    }
+
+    //flush everything to disk and move the file into place
+    pub fn commit(mut self) -> Result<(), std::io::Error> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        rename(&self.tmp, &self.target)?;
+        self.committed = true;
+        sync_parent(&self.target)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+//for formats that write a length once what it covers is written
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = remove_file(&self.tmp);
+        }
+    }
+}
+
+pub fn write_atomic(target: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = AtomicFile::create(target)?;
+    file.write_all(data)?;
+    file.commit()
+}
+
+//make the rename itself durable
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<(), std::io::Error> {
+    match path.parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
@@ -1,21 +1,73 @@
 //Binary Large OBject
 
-use crate::hex;
+//
+//ids are 32 bytes whatever the hash algorithm of the repository, so they print,
+//parse and serialize the same way. the algorithm is a setting of the repository
+//and the stores hash with it, see Hasher
+use crate::{config::HashAlgorithm, hex};
 use ::blake3::Hash;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     fmt::{Debug, Display},
-    fs::File,
-    io::Read,
-    path::Path,
+    io::Write,
+    str::FromStr,
 };
 
 #[derive(Clone, Copy, PartialEq, Eq)]
-pub struct Blob(Hash);
+pub struct Blob([u8; 32]);
+
+impl Blob {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Blob(bytes)
+    }
+
+    //parse a full lowercase hex id, as used in object file names
+    pub fn from_hex(s: &str) -> Option<Self> {
+        if s.len() != 64 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return None;
+        }
+        let v: Vec<u8> = hex::Hex(s.as_bytes().to_vec()).into();
+        let mut bytes: [u8; 32] = [0; 32];
+        bytes.copy_from_slice(&v);
+        Some(Blob::from_bytes(bytes))
+    }
+
+    //unkeyed blake3 of `bytes`, for checksums and names of files like packs and
+    //the stat index. objects are hashed by their store, see Hasher
+    pub fn checksum(bytes: &[u8]) -> Self {
+        Blob::from(blake3::hash(bytes))
+    }
+}
+
+//a full object id, in either case
+impl FromStr for Blob {
+    type Err = InvalidBlob;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Blob::from_hex(&s.to_ascii_lowercase()).ok_or_else(|| InvalidBlob(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidBlob(pub String);
+
+impl Display for InvalidBlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not an object id: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidBlob {}
 
 impl Ord for Blob {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.as_bytes().cmp(other.0.as_bytes())
+        self.0.cmp(&other.0)
     }
 }
 
@@ -25,21 +77,15 @@ impl PartialOrd for Blob {
     }
 }
 
-impl From<&Vec<u8>> for Blob {
-    fn from(vec: &Vec<u8>) -> Self {
-        Blob(blake3::hash(vec))
-    }
-}
-
-impl From<&[u8]> for Blob {
-    fn from(bytes: &[u8]) -> Self {
-        Blob(blake3::hash(bytes))
+impl From<Hash> for Blob {
+    fn from(hash: Hash) -> Self {
+        Blob(*hash.as_bytes())
     }
 }
 
 impl Display for Blob {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let b: &[u8] = self.0.as_bytes();
+        let b: &[u8] = &self.0;
         write!(f, "{}", hex::Hex::from(b))
     }
 }
@@ -49,7 +95,7 @@ impl Serialize for Blob {
     where
         S: serde::Serializer,
     {
-        let binary: &[u8] = self.0.as_bytes();
+        let binary: &[u8] = &self.0;
         hex::Hex::from(binary).serialize(serializer)
     }
 }
@@ -60,10 +106,9 @@ impl<'de> Deserialize<'de> for Blob {
         D: serde::Deserializer<'de>,
     {
         let binary: hex::Hex = Deserialize::deserialize(deserializer)?;
-        let v: Vec<u8> = binary.into();
-        let mut bytes: [u8; 32] = [0; 32];
-        bytes[..32].copy_from_slice(&v[..32]);
-        Ok(Blob(Hash::from(bytes)))
+        let s = String::from_utf8_lossy(&binary.0);
+        Blob::from_hex(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid blob id: {:?}", s)))
     }
 }
 
@@ -73,21 +118,65 @@ impl Debug for Blob {
     }
 }
 
-impl TryFrom<File> for Blob {
-    type Error = std::io::Error;
+//hashes content into an object id with the algorithm of a repository, or keyed
+//for encrypted stores
+#[derive(Clone)]
+pub enum Hasher {
+    //boxed, it is much larger than the others
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    HmacSha256(Hmac<Sha256>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    //the keyed variant of `algorithm`, ids don't reveal the content without the key
+    pub fn keyed(algorithm: HashAlgorithm, key: &[u8; 32]) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new_keyed(key))),
+            HashAlgorithm::Sha256 => Hasher::HmacSha256(
+                <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length"),
+            ),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) -> &mut Self {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Hasher::Sha256(hasher) => {
+                Digest::update(hasher, bytes);
+            }
+            Hasher::HmacSha256(hasher) => {
+                Mac::update(hasher, bytes);
+            }
+        }
+        self
+    }
 
-    fn try_from(mut f: File) -> Result<Self, Self::Error> {
-        let mut vec = Vec::new();
-        f.read_to_end(&mut vec)?;
-        Ok((&vec).into())
+    pub fn finalize(&self) -> Blob {
+        match self {
+            Hasher::Blake3(hasher) => hasher.finalize().into(),
+            Hasher::Sha256(hasher) => Blob(hasher.clone().finalize().into()),
+            Hasher::HmacSha256(hasher) => Blob(hasher.clone().finalize().into_bytes().into()),
+        }
     }
 }
 
-impl TryFrom<&Path> for Blob {
-    type Error = std::io::Error;
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
 
-    fn try_from(p: &Path) -> Result<Self, Self::Error> {
-        let f = File::options().read(true).open(p)?;
-        Blob::try_from(f)
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
@@ -0,0 +1,128 @@
+//content-defined chunking of large files
+//
+//a gear rolling hash runs over the data and a chunk ends where the hash has
+//its low bits all zero, so an edit only moves the chunk boundaries around it
+use std::io::{ErrorKind, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blob::Blob,
+    config::ChunkingConfig,
+    encoding::{write_blob, write_varint, Canonical, Reader},
+    objects::{ObjectKind, TypedObject},
+};
+
+//object listing the chunks of a file, in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub size: u64,
+    pub chunks: Vec<Blob>,
+}
+
+impl TypedObject for ChunkManifest {
+    const KIND: ObjectKind = ObjectKind::Manifest;
+}
+
+//size, chunk count, then the chunks in order
+impl Canonical for ChunkManifest {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.size);
+        write_varint(out, self.chunks.len() as u64);
+        for chunk in &self.chunks {
+            write_blob(out, chunk);
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, std::io::Error> {
+        let size = input.varint()?;
+        let mut chunks = Vec::new();
+        for _ in 0..input.varint()? {
+            chunks.push(input.blob()?);
+        }
+        Ok(ChunkManifest { size, chunks })
+    }
+}
+
+//pseudo random values for every byte, the same on every machine
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < 256 {
+        //splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+pub struct Chunker<'a> {
+    reader: &'a mut dyn Read,
+    buffer: Vec<u8>,
+    eof: bool,
+    min: usize,
+    max: usize,
+    mask: u64,
+}
+
+impl<'a> Chunker<'a> {
+    pub fn new(reader: &'a mut dyn Read, config: &ChunkingConfig) -> Self {
+        let max = config.max_chunk_size.max(1);
+        let min = config.min_chunk_size.min(max);
+        let avg = config.avg_chunk_size.max(2);
+        let bits = usize::BITS - 1 - avg.leading_zeros();
+        Chunker {
+            reader,
+            buffer: Vec::new(),
+            eof: false,
+            min,
+            max,
+            mask: (1u64 << bits) - 1,
+        }
+    }
+
+    //next chunk of the stream, None at its end
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, std::io::Error> {
+        while !self.eof && self.buffer.len() < self.max {
+            let start = self.buffer.len();
+            self.buffer.resize(self.max, 0);
+            match self.reader.read(&mut self.buffer[start..]) {
+                Ok(0) => {
+                    self.buffer.truncate(start);
+                    self.eof = true;
+                }
+                Ok(n) => self.buffer.truncate(start + n),
+                Err(err) if err.kind() == ErrorKind::Interrupted => self.buffer.truncate(start),
+                Err(err) => return Err(err),
+            }
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let cut = self.cut_point();
+        let rest = self.buffer.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+    }
+
+    fn cut_point(&self) -> usize {
+        let end = self.buffer.len().min(self.max);
+        if end <= self.min {
+            return end;
+        }
+        let mut hash = 0u64;
+        for (i, byte) in self.buffer[self.min..end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask == 0 {
+                return self.min + i + 1;
+            }
+        }
+        end
+    }
+}
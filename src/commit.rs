@@ -1,8 +1,10 @@
-use std::collections::BTreeSet;
-
 use serde::{Deserialize, Serialize};
 
-use crate::blob::Blob;
+use crate::{
+    blob::Blob,
+    encoding::{self, write_blob, write_bytes, write_varint, Canonical, Reader},
+    objects::{ObjectKind, TypedObject},
+};
 
 //Commit of a version.
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -11,6 +13,67 @@ pub struct Commit {
     pub message: String,
     // Blob of the directory structure.
     pub directory: Blob,
-    //link to previous blob
-    pub previous: BTreeSet<Blob>,
+    //previous commits, the first parent first
+    pub previous: Vec<Blob>,
+}
+
+impl TypedObject for Commit {
+    const KIND: ObjectKind = ObjectKind::Commit;
+}
+
+//message, directory, then the previous commits in order
+impl Canonical for Commit {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_bytes(out, self.message.as_bytes());
+        write_blob(out, &self.directory);
+        write_varint(out, self.previous.len() as u64);
+        for previous in &self.previous {
+            write_blob(out, previous);
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, std::io::Error> {
+        let message = input.string()?;
+        let directory = input.blob()?;
+        let mut previous = Vec::new();
+        for _ in 0..input.varint()? {
+            let parent = input.blob()?;
+            if previous.contains(&parent) {
+                return Err(encoding::invalid("commit lists a parent twice"));
+            }
+            previous.push(parent);
+        }
+        Ok(Commit {
+            message,
+            directory,
+            previous,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(previous: Vec<Blob>) -> Commit {
+        Commit {
+            message: "message".to_string(),
+            directory: Blob::from_bytes([1; 32]),
+            previous,
+        }
+    }
+
+    #[test]
+    fn parents_keep_their_order() {
+        let merge = commit(vec![Blob::from_bytes([3; 32]), Blob::from_bytes([2; 32])]);
+        let bytes = encoding::to_binary(&merge);
+        assert_eq!(encoding::from_binary::<Commit>(&bytes).unwrap(), merge);
+    }
+
+    #[test]
+    fn repeated_parents_are_rejected() {
+        let parent = Blob::from_bytes([2; 32]);
+        let bytes = encoding::to_binary(&commit(vec![parent, parent]));
+        assert!(encoding::from_binary::<Commit>(&bytes).is_err());
+    }
 }
@@ -0,0 +1,113 @@
+//repository settings, stored as json in .log/config
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::blob::Blob;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    //split large files into content-defined chunks, disabled when absent
+    pub chunking: Option<ChunkingConfig>,
+    //how new trees, commits and chunk lists are written, both are always readable
+    //repositories from before this setting keep writing json
+    pub encoding: ObjectEncoding,
+    //where objects are kept, chosen when the repository is created
+    pub store: ObjectStore,
+    pub cache: CacheConfig,
+    //.log folders of other repositories whose objects are read when missing here
+    //relative paths start from this .log folder
+    pub alternates: Vec<PathBuf>,
+    //encrypt objects at rest, see objects::encrypted
+    pub encryption: Option<EncryptionConfig>,
+    //keep matching files in the large file store, see large
+    pub large_files: Option<LargeFilesConfig>,
+    //algorithm object ids are computed with, chosen when the repository is created
+    //repositories from before this setting use blake3
+    pub hash: HashAlgorithm,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LargeFilesConfig {
+    //file names, `*` matches any run of characters
+    pub patterns: Vec<String>,
+    //files of at least this many bytes, whatever their name
+    pub min_size: Option<u64>,
+    //.log folder of a repository whose large files are fetched when missing here
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    //file with the key in hex, LOG_ENCRYPTION_KEY is used instead when set
+    pub key_file: Option<PathBuf>,
+    //derived from the key, a wrong key is refused instead of failing on every object
+    pub key_check: Blob,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectStore {
+    //one file per object under objects/, plus packs
+    #[default]
+    Directory,
+    //every object in the single file objects.data, see objects::append
+    Append,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectEncoding {
+    #[default]
+    Json,
+    //canonical binary encoding, see encoding
+    Binary,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    //bytes of objects kept in memory while a command runs, 0 disables the cache
+    pub budget: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            budget: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkingConfig {
+    //files of at least this many bytes are chunked
+    pub min_file_size: u64,
+    pub min_chunk_size: usize,
+    //rounded down to a power of two
+    pub avg_chunk_size: usize,
+    pub max_chunk_size: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        ChunkingConfig {
+            min_file_size: 8 * 1024 * 1024,
+            min_chunk_size: 256 * 1024,
+            avg_chunk_size: 1024 * 1024,
+            max_chunk_size: 4 * 1024 * 1024,
+        }
+    }
+}
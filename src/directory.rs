@@ -1,39 +1,368 @@
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, BTreeSet},
-    fs::{self, File},
-    io::Read,
+    fmt::Debug,
+    fs::{self, DirEntry, File},
+    io::Write,
     path::{Path, PathBuf},
 };
 
-use crate::{blob::Blob, objects::Objects};
+use crate::{
+    blob::Blob,
+    chunker::{ChunkManifest, Chunker},
+    config::{ChunkingConfig, Config, ObjectEncoding},
+    encoding::{self, write_blob, write_bytes, write_varint, Canonical, Reader},
+    index::StatIndex,
+    large::{self, LargeFiles},
+    objects::{ObjectKind, Objects, TypedObject},
+};
 
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 //directory tree, where leaves are blobs
+//entries are keyed by their name, paths are made of the names from the work tree
+//root down, joined with '/'
+//subdirectories are tree objects of their own, so an unchanged subdirectory keeps
+//its id and is shared between commits. trees from before format version 5 nest
+//them in place
 #[derive(Clone, PartialEq, Eq, Default, Deserialize, Serialize, Debug)]
 pub struct Directory {
     #[serde(flatten)]
     root: BTreeMap<String, DirectoryEntry>,
 }
 
+impl TypedObject for Directory {
+    const KIND: ObjectKind = ObjectKind::Tree;
+}
+
+//entry tags of the binary encoding
+//FILE entries from before sizes were recorded only hold the content
+const FILE: u8 = 0;
+const DIRECTORY: u8 = 1;
+const CHUNKED: u8 = 2;
+const LARGE: u8 = 3;
+//content and size
+const SIZED_FILE: u8 = 4;
+//in front of the entry of an executable file
+const EXECUTABLE: u8 = 5;
+const SYMLINK: u8 = 6;
+const TREE: u8 = 7;
+
+//deepest nesting of subdirectories written in place that is read, deeper trees
+//would overflow the stack
+const MAX_NESTING: usize = 256;
+
+//entry count, then name, tag and content of every entry in name order
+//nested subdirectories are written in place
+impl Canonical for Directory {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.root.len() as u64);
+        for (name, entry) in &self.root {
+            write_bytes(out, name.as_bytes());
+            entry.write(out);
+        }
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, std::io::Error> {
+        Directory::read_nested(input, 0)
+    }
+}
+
+impl Directory {
+    //names have to be in strictly increasing order, so a tree has one encoding
+    fn read_nested(input: &mut Reader, depth: usize) -> Result<Self, std::io::Error> {
+        if depth > MAX_NESTING {
+            return Err(encoding::invalid("tree is nested too deep"));
+        }
+        let mut root = BTreeMap::new();
+        for _ in 0..input.varint()? {
+            let name = input.string()?;
+            if root.last_key_value().is_some_and(|(last, _)| *last >= name) {
+                return Err(encoding::invalid("tree entries are not in name order"));
+            }
+            let entry = DirectoryEntry::read_nested(input, depth)?;
+            root.insert(name, entry);
+        }
+        Ok(Directory { root })
+    }
+}
+
+//tag and content of an entry, also used by the stat cache in index
+impl DirectoryEntry {
+    pub(crate) fn write(&self, out: &mut Vec<u8>) {
+        if self.mode() == FileMode::Executable {
+            out.push(EXECUTABLE);
+        }
+        match self {
+            DirectoryEntry::File {
+                content,
+                size: None,
+                ..
+            } => {
+                out.push(FILE);
+                write_blob(out, content);
+            }
+            DirectoryEntry::File {
+                content,
+                size: Some(size),
+                ..
+            } => {
+                out.push(SIZED_FILE);
+                write_blob(out, content);
+                write_varint(out, *size);
+            }
+            DirectoryEntry::Directory(directory) => {
+                out.push(DIRECTORY);
+                directory.write(out);
+            }
+            DirectoryEntry::Chunked {
+                content, manifest, ..
+            } => {
+                out.push(CHUNKED);
+                write_blob(out, content);
+                write_blob(out, manifest);
+            }
+            DirectoryEntry::Large { content, size, .. } => {
+                out.push(LARGE);
+                write_blob(out, content);
+                write_varint(out, *size);
+            }
+            DirectoryEntry::Symlink { target } => {
+                out.push(SYMLINK);
+                write_bytes(out, target.as_bytes());
+            }
+            DirectoryEntry::Tree(tree) => {
+                out.push(TREE);
+                write_blob(out, tree);
+            }
+        }
+    }
+
+    pub(crate) fn read(input: &mut Reader) -> Result<Self, std::io::Error> {
+        DirectoryEntry::read_nested(input, 0)
+    }
+
+    //`depth` is the nesting of the directory holding the entry
+    fn read_nested(input: &mut Reader, depth: usize) -> Result<Self, std::io::Error> {
+        let mut tag = input.byte()?;
+        let mode = match tag {
+            EXECUTABLE => {
+                tag = input.byte()?;
+                FileMode::Executable
+            }
+            _ => FileMode::Regular,
+        };
+        Ok(match tag {
+            FILE => DirectoryEntry::File {
+                content: input.blob()?,
+                mode,
+                size: None,
+            },
+            SIZED_FILE => DirectoryEntry::File {
+                content: input.blob()?,
+                mode,
+                size: Some(input.varint()?),
+            },
+            DIRECTORY if mode == FileMode::Regular => {
+                DirectoryEntry::Directory(Box::new(Directory::read_nested(input, depth + 1)?))
+            }
+            CHUNKED => DirectoryEntry::Chunked {
+                content: input.blob()?,
+                manifest: input.blob()?,
+                mode,
+            },
+            LARGE => DirectoryEntry::Large {
+                content: input.blob()?,
+                size: input.varint()?,
+                mode,
+            },
+            SYMLINK if mode == FileMode::Regular => DirectoryEntry::Symlink {
+                target: input.string()?,
+            },
+            TREE if mode == FileMode::Regular => DirectoryEntry::Tree(input.blob()?),
+            _ => return Err(encoding::invalid("unknown tree entry")),
+        })
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(from = "StoredEntry")]
 pub enum DirectoryEntry {
-    File(Blob),
+    //size is None in trees from before it was recorded
+    File {
+        content: Blob,
+        #[serde(default, skip_serializing_if = "FileMode::is_regular")]
+        mode: FileMode,
+        size: Option<u64>,
+    },
     Directory(Box<Directory>),
+    //large file stored as chunks listed in a manifest object, see chunker
+    //content is the blob of the whole file, so it compares equal to a plain File
+    Chunked {
+        content: Blob,
+        manifest: Blob,
+        #[serde(default, skip_serializing_if = "FileMode::is_regular")]
+        mode: FileMode,
+    },
+    //file kept in the large file store, see large
+    //content has the id a plain File would have
+    Large {
+        content: Blob,
+        size: u64,
+        #[serde(default, skip_serializing_if = "FileMode::is_regular")]
+        mode: FileMode,
+    },
+    //symbolic link, recorded and recreated as it is, never followed
+    Symlink { target: String },
+    //subdirectory stored as a tree object of its own
+    Tree(Blob),
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileMode {
+    #[default]
+    Regular,
+    Executable,
+}
+
+impl FileMode {
+    //executable when anyone may execute it, always regular where there is no such bit
+    pub fn of(meta: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        if std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o111 != 0 {
+            return FileMode::Executable;
+        }
+        let _ = meta;
+        FileMode::Regular
+    }
+
+    fn is_regular(&self) -> bool {
+        *self == FileMode::Regular
+    }
+
+    //set or clear the execute bits, execution is allowed to whoever may read
+    pub fn apply(self, path: &Path) -> Result<(), std::io::Error> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = fs::metadata(path)?.permissions();
+            let bits = permissions.mode();
+            permissions.set_mode(match self {
+                FileMode::Regular => bits & !0o111,
+                FileMode::Executable => bits | ((bits & 0o444) >> 2),
+            });
+            fs::set_permissions(path, permissions)?;
+        }
+        let _ = (self, path);
+        Ok(())
+    }
+}
+
+//json of tree entries, where files from before modes and sizes are just the id
+#[derive(Deserialize)]
+enum StoredEntry {
+    File(StoredFile),
+    Directory(Box<Directory>),
+    Chunked {
+        content: Blob,
+        manifest: Blob,
+        #[serde(default)]
+        mode: FileMode,
+    },
+    Large {
+        content: Blob,
+        size: u64,
+        #[serde(default)]
+        mode: FileMode,
+    },
+    Symlink {
+        target: String,
+    },
+    Tree(Blob),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredFile {
+    Legacy(Blob),
+    Current {
+        content: Blob,
+        #[serde(default)]
+        mode: FileMode,
+        size: Option<u64>,
+    },
+}
+
+impl From<StoredEntry> for DirectoryEntry {
+    fn from(entry: StoredEntry) -> Self {
+        match entry {
+            StoredEntry::File(StoredFile::Legacy(content)) => DirectoryEntry::File {
+                content,
+                mode: FileMode::Regular,
+                size: None,
+            },
+            StoredEntry::File(StoredFile::Current {
+                content,
+                mode,
+                size,
+            }) => DirectoryEntry::File {
+                content,
+                mode,
+                size,
+            },
+            StoredEntry::Directory(directory) => DirectoryEntry::Directory(directory),
+            StoredEntry::Chunked {
+                content,
+                manifest,
+                mode,
+            } => DirectoryEntry::Chunked {
+                content,
+                manifest,
+                mode,
+            },
+            StoredEntry::Large {
+                content,
+                size,
+                mode,
+            } => DirectoryEntry::Large {
+                content,
+                size,
+                mode,
+            },
+            StoredEntry::Symlink { target } => DirectoryEntry::Symlink { target },
+            StoredEntry::Tree(tree) => DirectoryEntry::Tree(tree),
+        }
+    }
+}
+
+//changes from one tree to another, keyed by name like the trees
+//deleted and added entries are whole, modified ones only hold what changed
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Diff {
     pub deleted: BTreeMap<String, DirectoryEntry>,
     pub added: BTreeMap<String, DirectoryEntry>,
-    // pub modified: BTreeMap<String, DiffEntry>,
-    pub modified: BTreeMap<String, DirectoryEntry>,
+    pub modified: BTreeMap<String, DiffEntry>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DiffEntry {
-    File(Blob),
+    //file or symlink with another content, mode or target, the new version
+    File(DirectoryEntry),
+    //directory on both sides, never empty
     Directory(Box<Diff>),
+    //file or symlink on one side, directory on the other
+    TypeChange {
+        from: DirectoryEntry,
+        to: DirectoryEntry,
+    },
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty() && self.added.is_empty() && self.modified.is_empty()
+    }
 }
 
 //list of ignored files from all levels
@@ -50,306 +379,907 @@ impl Default for Ignores {
     }
 }
 
-#[derive(Debug)]
 pub enum Error<Store: Objects> {
     ObjectMissing(Blob),
+    WrongKind(Blob, ObjectKind),
     Store(Store::Error),
     IO(std::io::Error),
 }
 
-impl DirectoryEntry {
-    pub fn diff(&self, other: &DirectoryEntry) -> Option<DiffEntry> {
-        match (self, other) {
-            (DirectoryEntry::File(blob_s), DirectoryEntry::File(blob_o)) => {
-                if blob_s != blob_o {
-                    Some(DiffEntry::File(*blob_o))
-                } else {
-                    None
-                }
-            }
-            (DirectoryEntry::Directory(_), DirectoryEntry::File(blob_o)) => {
-                Some(DiffEntry::File(*blob_o))
-            }
-            (DirectoryEntry::File(_), DirectoryEntry::Directory(d)) => {
-                Some(DiffEntry::Directory(Box::new(Diff {
-                    deleted: BTreeMap::new(),
-                    added: d.root.clone(),
-                    modified: BTreeMap::new(),
-                })))
-            }
-            (DirectoryEntry::Directory(d_s), DirectoryEntry::Directory(d_o)) => {
-                if d_s == d_o {
-                    Some(DiffEntry::Directory(Box::new(d_s.diff(d_o))))
-                } else {
-                    None
-                }
+impl<Store: Objects> Debug for Error<Store>
+where
+    Store::Error: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ObjectMissing(blob) => f.debug_tuple("ObjectMissing").field(blob).finish(),
+            Error::WrongKind(blob, kind) => {
+                f.debug_tuple("WrongKind").field(blob).field(kind).finish()
             }
+            Error::Store(err) => f.debug_tuple("Store").field(err).finish(),
+            Error::IO(err) => f.debug_tuple("IO").field(err).finish(),
         }
     }
 }
 
-impl Directory {
+impl DirectoryEntry {
+    //blob of the file content, None for directories
+    pub fn content(&self) -> Option<Blob> {
+        match self {
+            DirectoryEntry::File { content, .. } => Some(*content),
+            DirectoryEntry::Chunked { content, .. } => Some(*content),
+            DirectoryEntry::Large { content, .. } => Some(*content),
+            DirectoryEntry::Directory(_) | DirectoryEntry::Tree(_) | DirectoryEntry::Symlink { .. } => None,
+        }
+    }
 
-    pub fn build_branch_working_dir(&self, branch_tree: &Directory, root: PathBuf) {
-        let diff = self.diff(branch_tree);
-        //this keeps intact files that are the same
-        //deletes files that are in current working copy but not in branch tree
-        //adds files that are in branche tree but not in working copy
-
-        for (entry_name, entry_value) in diff.deleted {
-            if let DirectoryEntry::File(_) = entry_value {
-                fs::remove_file(entry_name).expect("error at removing file");
-            } else if let DirectoryEntry::Directory(_) = entry_value {
-                fs::remove_dir_all(entry_name).expect("error at removing folder");
+    //regular for directories and symlinks
+    pub fn mode(&self) -> FileMode {
+        match self {
+            DirectoryEntry::File { mode, .. }
+            | DirectoryEntry::Chunked { mode, .. }
+            | DirectoryEntry::Large { mode, .. } => *mode,
+            DirectoryEntry::Directory(_) | DirectoryEntry::Tree(_) | DirectoryEntry::Symlink { .. } => {
+                FileMode::Regular
             }
         }
+    }
 
-        add_files(&Directory { root: diff.added }, &root);
-        update_files(
-            &Directory {
-                root: diff.modified,
-            },
-            &root,
-        );
+    //files and symlinks
+    pub fn is_leaf(&self) -> bool {
+        !matches!(self, DirectoryEntry::Directory(_) | DirectoryEntry::Tree(_))
     }
 
-    pub fn merge_branches(&self, branch_tree: &Directory, root: PathBuf) {
-        let diff = self.diff(branch_tree);
+    //the directory of a Directory or Tree entry, None for leaves
+    pub fn subtree<Store: Objects>(&self, store: &Store) -> Result<Option<Cow<'_, Directory>>, Error<Store>> {
+        match self {
+            DirectoryEntry::Directory(directory) => Ok(Some(Cow::Borrowed(directory))),
+            DirectoryEntry::Tree(tree) => Ok(Some(Cow::Owned(read_tree(store, *tree)?))),
+            _ => Ok(None),
+        }
+    }
 
-        add_files(&Directory { root: diff.added }, &root);
-        solve_conflicts(
-            &Directory {
-                root: diff.modified,
-            },
-            &root,
-        );
+    //subtrees stored under the same id, which hold the same entries
+    fn same_tree(&self, other: &DirectoryEntry) -> bool {
+        matches!((self, other), (DirectoryEntry::Tree(tree), DirectoryEntry::Tree(other)) if tree == other)
     }
 
-    pub fn diff(&self, other: &Directory) -> Diff {
-        let added = get_added(self, other);
-        let deleted = get_deleted(self, other);
-        let modified = get_changes(self, other);
+    //same content and mode, or the same link target
+    //how a file is stored and whether its size is known don't matter
+    fn same_leaf(&self, other: &DirectoryEntry) -> bool {
+        match (self, other) {
+            (DirectoryEntry::Symlink { target }, DirectoryEntry::Symlink { target: other }) => {
+                target == other
+            }
+            (DirectoryEntry::Symlink { .. }, _) | (_, DirectoryEntry::Symlink { .. }) => false,
+            _ => self.content() == other.content() && self.mode() == other.mode(),
+        }
+    }
 
-        Diff {
-            added,
-            deleted,
-            modified,
+    //what changed from this entry to `other`, None when nothing did
+    pub fn diff<Store: Objects>(
+        &self,
+        other: &DirectoryEntry,
+        store: &Store,
+    ) -> Result<Option<DiffEntry>, Error<Store>> {
+        if self.is_leaf() && other.is_leaf() {
+            return Ok((!self.same_leaf(other)).then(|| DiffEntry::File(other.clone())));
         }
+        if self.same_tree(other) {
+            return Ok(None);
+        }
+        Ok(match (self.subtree(store)?, other.subtree(store)?) {
+            (Some(d_s), Some(d_o)) => {
+                let diff = d_s.diff(&d_o, store)?;
+                (!diff.is_empty()).then(|| DiffEntry::Directory(Box::new(diff)))
+            }
+            _ => Some(DiffEntry::TypeChange {
+                from: self.clone(),
+                to: other.clone(),
+            }),
+        })
     }
 }
 
-fn get_added(main: &Directory, other: &Directory) -> BTreeMap<String, DirectoryEntry> {
-    let mut added: BTreeMap<String, DirectoryEntry> = BTreeMap::new();
-    for (entry_name, entry_obj) in &other.root {
-        if let DirectoryEntry::File(_) = entry_obj {
-            if !main.root.contains_key(entry_name) {
-                if let Some(entry_value) = other.root.get(entry_name) {
-                    added.insert(entry_name.clone(), entry_value.clone());
-                }
+impl Directory {
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &DirectoryEntry)> {
+        self.root.iter()
+    }
+
+    //before format version 2, entries were keyed by the path they were snapshotted
+    //from, mostly absolute. only the last component is kept, which leaves newer
+    //trees as they are
+    pub fn into_relative(self) -> Directory {
+        let root = self
+            .root
+            .into_iter()
+            .map(|(key, entry)| {
+                let name = Path::new(&key)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or(key);
+                let entry = match entry {
+                    DirectoryEntry::Directory(dir) => {
+                        DirectoryEntry::Directory(Box::new(dir.into_relative()))
+                    }
+                    entry => entry,
+                };
+                (name, entry)
+            })
+            .collect();
+        Directory { root }
+    }
+
+    //`root` is the work tree both trees were taken from
+    pub fn build_branch_working_dir<Store: Objects>(
+        &self,
+        branch_tree: &Directory,
+        root: &Path,
+        store: &Store,
+        large: Option<&LargeFiles>,
+    ) where
+        Store::Error: Debug,
+    {
+        let diff = self.diff(branch_tree, store).expect("error at comparing trees");
+        update_files(diff, root, store, large);
+    }
+
+    pub fn merge_branches<Store: Objects>(
+        &self,
+        branch_tree: &Directory,
+        root: &Path,
+        store: &Store,
+        large: Option<&LargeFiles>,
+    ) where
+        Store::Error: Debug,
+    {
+        let diff = self.diff(branch_tree, store).expect("error at comparing trees");
+        solve_conflicts(diff, root, "", store, large);
+    }
+
+    //subtrees stored under the same id are the same and aren't walked
+    pub fn diff<Store: Objects>(&self, other: &Directory, store: &Store) -> Result<Diff, Error<Store>> {
+        let mut diff = Diff::default();
+        for (entry_name, entry_obj) in &self.root {
+            if !other.root.contains_key(entry_name) {
+                diff.deleted.insert(entry_name.clone(), entry_obj.clone());
             }
-        } else if let DirectoryEntry::Directory(dir_o) = entry_obj {
-            if !main.root.contains_key(entry_name) {
-                if let Some(entry_value) = other.root.get(entry_name) {
-                    added.insert(entry_name.clone(), entry_value.clone());
+        }
+        for (entry_name, entry_obj) in &other.root {
+            match self.root.get(entry_name) {
+                None => {
+                    diff.added.insert(entry_name.clone(), entry_obj.clone());
+                }
+                Some(entry_value) => {
+                    if let Some(change) = entry_value.diff(entry_obj, store)? {
+                        diff.modified.insert(entry_name.clone(), change);
+                    }
                 }
-            } else if let Some(DirectoryEntry::Directory(dir_s)) = main.root.get(entry_name) {
-                // if let DirectoryEntry::Directory(dir_s) = entry_value {
-                added.extend(
-                    get_added(dir_s, dir_o)
-                        .into_iter()
-                        .map(|(k, v)| (k.to_owned(), v)),
-                );
-                // }
             }
         }
+        Ok(diff)
     }
-    added
 }
 
-fn get_deleted(main: &Directory, other: &Directory) -> BTreeMap<String, DirectoryEntry> {
-    let mut deleted: BTreeMap<String, DirectoryEntry> = BTreeMap::new();
-    for (entry_name, entry_obj) in &main.root {
-        if let DirectoryEntry::File(_) = entry_obj {
-            if !other.root.contains_key(entry_name) {
-                if let Some(entry_value) = main.root.get(entry_name) {
-                    deleted.insert(entry_name.clone(), entry_value.clone());
-                }
+//turn the directory at `root` from one side of `diff` into the other
+//this keeps intact files that are the same
+//deletes files that are in current working copy but not in branch tree
+//adds files that are in branche tree but not in working copy
+pub fn update_files<Store: Objects>(
+    diff: Diff,
+    root: &Path,
+    store: &Store,
+    large: Option<&LargeFiles>,
+) where
+    Store::Error: Debug,
+{
+    for (entry_name, entry_value) in &diff.deleted {
+        let Some(path) = checked_path(root, entry_name, !entry_value.is_leaf()) else {
+            continue;
+        };
+        remove_entry(entry_value, &path);
+    }
+    add_files(&Directory { root: diff.added }, root, store, large);
+    for (entry_name, change) in diff.modified {
+        match change {
+            DiffEntry::File(entry_value) => {
+                let Some(path) = checked_path(root, &entry_name, false) else {
+                    continue;
+                };
+                std::fs::remove_file(&path).expect("");
+                write_entry(store, large, &entry_value, &path);
             }
-        } else if let DirectoryEntry::Directory(dir_s) = entry_obj {
-            if !other.root.contains_key(entry_name) {
-                if let Some(entry_value) = main.root.get(entry_name) {
-                    deleted.insert(entry_name.clone(), entry_value.clone());
-                }
-            } else if let Some(DirectoryEntry::Directory(dir_o)) = other.root.get(entry_name) {
-                // if let DirectoryEntry::Directory(dir_o) = entry_value {
-                deleted.extend(
-                    get_deleted(dir_s, dir_o)
-                        .into_iter()
-                        .map(|(k, v)| (k.to_owned(), v)),
-                );
-                // }
+            DiffEntry::Directory(diff) => {
+                let Some(path) = checked_path(root, &entry_name, true) else {
+                    continue;
+                };
+                update_files(*diff, &path, store, large);
+            }
+            DiffEntry::TypeChange { from, to } => {
+                let Some(path) = checked_path(root, &entry_name, !from.is_leaf()) else {
+                    continue;
+                };
+                remove_entry(&from, &path);
+                let added = Directory {
+                    root: BTreeMap::from([(entry_name, to)]),
+                };
+                add_files(&added, root, store, large);
             }
         }
     }
-    deleted
 }
 
-fn get_changes(main: &Directory, other: &Directory) -> BTreeMap<String, DirectoryEntry> {
-    let mut changes: BTreeMap<String, DirectoryEntry> = BTreeMap::new();
-    for (entry_name, entry_obj) in &other.root {
-        if let DirectoryEntry::File(_) = entry_obj {
-            if main.root.contains_key(entry_name) {
-                if let Some(entry_value) = main.root.get(entry_name) {
-                    if let (DirectoryEntry::File(hash_main), DirectoryEntry::File(hash_other)) =
-                        (entry_value, entry_obj)
-                    {
-                        if hash_main != hash_other {
-                            changes.insert(entry_name.clone(), entry_obj.clone());
+fn remove_entry(entry: &DirectoryEntry, path: &Path) {
+    if entry.is_leaf() {
+        fs::remove_file(path).expect("error at removing file");
+    } else {
+        fs::remove_dir_all(path).expect("error at removing folder");
+    }
+}
+
+//deletions aren't merged, additions are, and the user picks the version of
+//changed files. `prefix` is the path of `root` in the work tree
+pub fn solve_conflicts<Store: Objects>(
+    diff: Diff,
+    root: &Path,
+    prefix: &str,
+    store: &Store,
+    large: Option<&LargeFiles>,
+) where
+    Store::Error: Debug,
+{
+    add_files(&Directory { root: diff.added }, root, store, large);
+    for (entry_name, change) in diff.modified {
+        let entry_path = join_path(prefix, &entry_name);
+        match change {
+            DiffEntry::File(entry_value) => {
+                let Some(path) = checked_path(root, &entry_name, false) else {
+                    continue;
+                };
+                println!(
+                    "CONFLICT {}:\nif you want to keep current version enter [yes|no]",
+                    entry_path
+                );
+                let mut buffer = String::new();
+                match std::io::stdin().read_line(&mut buffer) {
+                    Ok(_) => {
+                        if buffer == "yes\r\n" {
+                            println!("current version of {} will be keeped", entry_path);
+                        } else if buffer == "no\r\n" {
+                            std::fs::remove_file(&path).expect("");
+                            write_entry(store, large, &entry_value, &path);
+                            println!("version of {} was replaced", entry_path);
+                        } else {
+                            println!("unrecognized answer, {} will remain unchanged", entry_path);
                         }
                     }
+                    Err(err) => {
+                        //in case of error, current version will be keeped
+                        println!("an error occured while receaving user answer: {}", err);
+                        println!("current version of {} will be keeped", entry_path);
+                    }
                 }
             }
-        } else if let DirectoryEntry::Directory(dir_o) = entry_obj {
-            if main.root.contains_key(entry_name) {
-                if let Some(DirectoryEntry::Directory(dir_s)) = main.root.get(entry_name) {
-                    // if let DirectoryEntry::Directory(dir_s) = entry_value {
-                    changes.extend(
-                        get_changes(dir_s, dir_o)
-                            .into_iter()
-                            .map(|(k, v)| (k.to_owned(), v)),
-                    );
-                    // }
-                }
+            DiffEntry::Directory(diff) => {
+                let Some(path) = checked_path(root, &entry_name, true) else {
+                    continue;
+                };
+                solve_conflicts(*diff, &path, &entry_path, store, large);
             }
+            DiffEntry::TypeChange { .. } => println!(
+                "CONFLICT {}: a file on one side and a directory on the other, current version kept",
+                entry_path
+            ),
         }
     }
-    changes
-}
-
-pub fn update_files(u_tree: &Directory, root: &PathBuf) {
-    for (entry_name, entry_value) in &u_tree.root {
-        if let DirectoryEntry::File(file_blob) = entry_value {
-            let blob_hash = format!("{}", file_blob);
-            let blob_folder_name = &blob_hash[0..2];
-            let blob_filename = &blob_hash[2..];
-            let path_to_blob = root
-                .join("objects")
-                .join(blob_folder_name)
-                .join(blob_filename);
-            std::fs::remove_file(entry_name).expect("");
-            std::fs::copy(path_to_blob, entry_name)
-                .expect("error at rebuilding branch working tree");
-        } else if let DirectoryEntry::Directory(dir_entry) = entry_value {
-            update_files(dir_entry, root);
+}
+
+pub fn add_files<Store: Objects>(
+    a_tree: &Directory,
+    root: &Path,
+    store: &Store,
+    large: Option<&LargeFiles>,
+) where
+    Store::Error: Debug,
+{
+    for (entry_name, entry_value) in &a_tree.root {
+        let Some(path) = checked_path(root, entry_name, !entry_value.is_leaf()) else {
+            continue;
+        };
+        if entry_value.is_leaf() {
+            write_entry(store, large, entry_value, &path);
+        } else if let Some(dir_entry) = entry_value.subtree(store).expect("error at reading tree") {
+            std::fs::create_dir_all(&path).expect("");
+            add_files(&dir_entry, &path, store, large);
         }
     }
 }
 
-pub fn solve_conflicts(u_tree: &Directory, root: &PathBuf) {
-    for (entry_name, entry_value) in &u_tree.root {
-        if let DirectoryEntry::File(file_blob) = entry_value {
-            let blob_hash = format!("{}", file_blob);
-            let blob_folder_name = &blob_hash[0..2];
-            let blob_filename = &blob_hash[2..];
-            let path_to_blob = root
-                .join("objects")
-                .join(blob_folder_name)
-                .join(blob_filename);
-            println!(
-                "CONFLICT {}:\nif you want to keep current version enter [yes|no]",
-                entry_name
-            );
-            let mut buffer = String::new();
-            match std::io::stdin().read_line(&mut buffer) {
-                Ok(_) => {
-                    if buffer == "yes\r\n" {
-                        println!("current version of {} will be keeped", entry_name);
-                    } else if buffer == "no\r\n" {
-                        std::fs::remove_file(entry_name).expect("");
-                        std::fs::copy(path_to_blob, entry_name)
-                            .expect("error at rebuilding branch working tree");
-                        println!("version of {} was replaced", entry_name);
-                    } else {
-                        println!("unrecognized answer, {} will remain unchanged", entry_name);
-                    }
+//path of `name` in the directory at `prefix`, the root being ""
+pub fn join_path(prefix: &str, name: &str) -> String {
+    match prefix {
+        "" => name.to_string(),
+        prefix => format!("{}/{}", prefix, name),
+    }
+}
+
+//whether `name` is a single path component, which is all trees may hold
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+//the file at `path` under `root`, None when the path could lead out of `root`
+//either through its names or through a symlink in the work tree on the way
+pub fn work_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut out = root.to_path_buf();
+    let mut names = path.split('/').peekable();
+    while let Some(name) = names.next() {
+        if !valid_name(name) {
+            return None;
+        }
+        out.push(name);
+        if names.peek().is_some() && is_symlink(&out) {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+}
+
+//trees come from other repositories too, so paths are checked before writing
+//a directory is written into, so it can't be a symlink either
+fn checked_path(root: &Path, path: &str, directory: bool) -> Option<PathBuf> {
+    let checked = work_path(root, path).filter(|checked| !directory || !is_symlink(checked));
+    if checked.is_none() {
+        println!("{} is not a path inside the work tree, skipped", path);
+    }
+    checked
+}
+
+//objects may be stored compressed, so file contents always go through the store
+//they are streamed, so large files are never held in memory whole
+fn write_entry<Store: Objects>(
+    store: &Store,
+    large: Option<&LargeFiles>,
+    entry: &DirectoryEntry,
+    path: &Path,
+) where
+    Store::Error: Debug,
+{
+    //replaced rather than followed
+    if is_symlink(path) {
+        fs::remove_file(path).expect("error at removing symlink");
+    }
+    if let DirectoryEntry::Symlink { target } = entry {
+        create_symlink(target, path).expect("error at creating symlink");
+        return;
+    }
+    let mut file = File::create(path).expect("error at rebuilding branch working tree");
+    match entry {
+        DirectoryEntry::File { content, .. } => copy_blob(store, *content, &mut file),
+        DirectoryEntry::Chunked { manifest, .. } => {
+            let manifest = read_manifest(store, *manifest).expect("error at reading chunk list");
+            for chunk in manifest.chunks {
+                copy_blob(store, chunk, &mut file);
+            }
+        }
+        DirectoryEntry::Large { content, size, .. } => {
+            let reader = large.and_then(|large| {
+                large
+                    .get_reader(*content)
+                    .expect("error at reading large file")
+            });
+            match reader {
+                Some(mut reader) => {
+                    std::io::copy(&mut reader, &mut file)
+                        .expect("error at rebuilding branch working tree");
                 }
-                Err(err) => {
-                    //in case of error, current version will be keeped
-                    println!("an error occured while receaving user answer: {}", err);
-                    println!("current version of {} will be keeped", entry_name);
+                None => {
+                    file.write_all(large::placeholder(*content, *size).as_bytes())
+                        .expect("error at rebuilding branch working tree");
+                    println!("{} is not available here, left a placeholder", path.display());
                 }
             }
-        } else if let DirectoryEntry::Directory(dir_entry) = entry_value {
-            solve_conflicts(dir_entry, root);
         }
+        DirectoryEntry::Directory(_) | DirectoryEntry::Tree(_) | DirectoryEntry::Symlink { .. } => {}
     }
+    entry
+        .mode()
+        .apply(path)
+        .expect("error at setting the file mode");
 }
 
-pub fn add_files(a_tree: &Directory, root: &PathBuf) {
-    for (entry_name, entry_value) in &a_tree.root {
-        if let DirectoryEntry::File(file_blob) = entry_value {
-            let blob_hash = format!("{}", file_blob);
-            let blob_folder_name = &blob_hash[0..2];
-            let blob_filename = &blob_hash[2..];
-            let path_to_blob = root
-                .join("objects")
-                .join(blob_folder_name)
-                .join(blob_filename);
-            std::fs::copy(path_to_blob, entry_name)
-                .expect("error at rebuilding branch working tree");
-        } else if let DirectoryEntry::Directory(dir_entry) = entry_value {
-            std::fs::create_dir_all(entry_name.clone()).expect("");
-            add_files(dir_entry, root);
+//the target is written as recorded, links leading out of the work tree included,
+//checkout never writes through them
+fn create_symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, path);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(target, path);
+    #[allow(unreachable_code)]
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks are not supported on this platform",
+    ))
+}
+
+fn copy_blob<Store: Objects>(store: &Store, blob: Blob, file: &mut File)
+where
+    Store::Error: Debug,
+{
+    let mut content = store
+        .get_reader(blob)
+        .expect("error at reading object")
+        .expect("object is missing from the store");
+    std::io::copy(&mut content, file).expect("error at rebuilding branch working tree");
+}
+
+pub fn read_tree<Store: Objects>(store: &Store, tree: Blob) -> Result<Directory, Error<Store>> {
+    match store.get_typed(tree).map_err(Error::Store)? {
+        None => Err(Error::ObjectMissing(tree)),
+        Some((Some(kind), _)) if kind != ObjectKind::Tree => Err(Error::WrongKind(tree, kind)),
+        Some((_, v)) => encoding::decode(&v).map_err(Error::IO),
+    }
+}
+
+pub fn read_manifest<Store: Objects>(
+    store: &Store,
+    manifest: Blob,
+) -> Result<ChunkManifest, Error<Store>> {
+    match store.get_typed(manifest).map_err(Error::Store)? {
+        None => Err(Error::ObjectMissing(manifest)),
+        Some((Some(kind), _)) if kind != ObjectKind::Manifest => {
+            Err(Error::WrongKind(manifest, kind))
         }
+        Some((_, v)) => encoding::decode(&v).map_err(Error::IO),
+    }
+}
+
+//store the file chunk by chunk, so that only chunks changed since an earlier version take space
+fn push_chunked<Store: Objects>(
+    store: &Store,
+    file: &mut File,
+    config: &ChunkingConfig,
+    object_encoding: ObjectEncoding,
+    mode: FileMode,
+) -> Result<DirectoryEntry, Error<Store>> {
+    let mut hasher = store.hasher();
+    let mut size = 0;
+    let mut chunks = Vec::new();
+    let mut chunker = Chunker::new(file, config);
+    while let Some(chunk) = chunker.next_chunk().map_err(Error::IO)? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        chunks.push(store.push(&chunk).map_err(Error::Store)?);
     }
+    let manifest = encoding::encode(&ChunkManifest { size, chunks }, object_encoding)
+        .map_err(|err| Error::IO(err.into()))?;
+    Ok(DirectoryEntry::Chunked {
+        content: hasher.finalize(),
+        manifest: store
+            .push_typed(ObjectKind::Manifest, &manifest)
+            .map_err(Error::Store)?,
+        mode,
+    })
 }
 
 impl Directory {
     pub fn new<Store: Objects>(
         dir: &Path,
         ignores: &Ignores,
-        store: &mut Store,
-    ) -> Result<Self, Error<Store>> {
-        let mut root = BTreeMap::new();
-        for f in std::fs::read_dir(dir).map_err(Error::IO)? {
-            let dir_entry = f.map_err(Error::IO)?;
-            if ignores
-                .set
-                .contains(&dir_entry.file_name().into_string().unwrap())
-            {
-                continue;
+        config: &Config,
+        store: &Store,
+    ) -> Result<Self, Error<Store>>
+    where
+        Store::Error: Send,
+    {
+        Directory::snapshot(dir, "", ignores, config, store, None, None)
+    }
+
+    //same as new, but files unchanged since they were recorded in `index` are not read again
+    //and files matching the large file settings are kept in `large`
+    pub fn new_indexed<Store: Objects>(
+        dir: &Path,
+        ignores: &Ignores,
+        config: &Config,
+        store: &Store,
+        index: &StatIndex,
+        large: Option<&LargeFiles>,
+    ) -> Result<Self, Error<Store>>
+    where
+        Store::Error: Send,
+    {
+        Directory::snapshot(dir, "", ignores, config, store, Some(index), large)
+    }
+
+    //entries are read, hashed and stored in parallel, then collected by name,
+    //so the result doesn't depend on the order in which threads finish
+    //`prefix` is the path of `dir` in the work tree
+    fn snapshot<Store: Objects>(
+        dir: &Path,
+        prefix: &str,
+        ignores: &Ignores,
+        config: &Config,
+        store: &Store,
+        index: Option<&StatIndex>,
+        large: Option<&LargeFiles>,
+    ) -> Result<Self, Error<Store>>
+    where
+        Store::Error: Send,
+    {
+        let dir_entries = std::fs::read_dir(dir)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .map_err(Error::IO)?;
+        let root = dir_entries
+            .into_par_iter()
+            .filter(|dir_entry| {
+                !ignores
+                    .set
+                    .contains(&dir_entry.file_name().into_string().unwrap())
+            })
+            .filter_map(|dir_entry| {
+                let name = dir_entry.file_name().to_string_lossy().to_string();
+                let path = join_path(prefix, &name);
+                Directory::new_entry(&dir_entry, &path, ignores, config, store, index, large)
+                    .map(|entry| entry.map(|entry| (name, entry)))
+                    .transpose()
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        Ok(Directory { root })
+    }
+
+    fn new_entry<Store: Objects>(
+        dir_entry: &DirEntry,
+        path: &str,
+        ignores: &Ignores,
+        config: &Config,
+        store: &Store,
+        index: Option<&StatIndex>,
+        large: Option<&LargeFiles>,
+    ) -> Result<Option<DirectoryEntry>, Error<Store>>
+    where
+        Store::Error: Send,
+    {
+        let file_type = dir_entry.file_type().map_err(Error::IO)?;
+        if file_type.is_dir() {
+            let directory =
+                Directory::snapshot(dir_entry.path().as_path(), path, ignores, config, store, index, large)?;
+            let tree = encoding::encode(&directory, config.encoding).map_err(|err| Error::IO(err.into()))?;
+            Ok(Some(DirectoryEntry::Tree(
+                store.push_typed(ObjectKind::Tree, &tree).map_err(Error::Store)?,
+            )))
+        } else if file_type.is_file() {
+            //taken before reading, so a change during the read shows up next time
+            let meta = dir_entry.metadata().map_err(Error::IO)?;
+            if let Some(entry) = index.and_then(|index| index.lookup(path, &meta)) {
+                //the object may have been pruned since, then the file is stored again
+                let stored = match &entry {
+                    DirectoryEntry::File { content, .. } => Some(*content),
+                    DirectoryEntry::Chunked { manifest, .. } => Some(*manifest),
+                    //large files may be pruned on purpose, the entry stays valid
+                    DirectoryEntry::Large { .. } => return Ok(Some(entry)),
+                    DirectoryEntry::Directory(_) | DirectoryEntry::Tree(_) | DirectoryEntry::Symlink { .. } => {
+                        None
+                    }
+                };
+                if let Some(stored) = stored {
+                    if store.exists(stored).map_err(Error::Store)? {
+                        return Ok(Some(entry));
+                    }
+                }
             }
-            let file_type = dir_entry.file_type().map_err(Error::IO)?;
-            if file_type.is_dir() {
-                let directory = Directory::new(dir_entry.path().as_path(), ignores, store)?;
-                root.insert(
-                    dir.join(dir_entry.file_name())
-                        .to_string_lossy()
-                        .to_string(),
-                    DirectoryEntry::Directory(Box::new(directory)),
-                );
-            } else if file_type.is_file() {
-                let id = Blob::try_from(dir_entry.path().as_path()).map_err(Error::IO)?;
-                root.insert(
-                    dir.join(dir_entry.file_name())
-                        .to_string_lossy()
-                        .to_string(),
-                    DirectoryEntry::File(id),
-                );
-                let mut v = Vec::new();
-                let mut obj_file = File::options()
-                    .read(true)
-                    .open(dir_entry.path())
-                    .map_err(Error::IO)?;
-                obj_file.read_to_end(&mut v).map_err(Error::IO)?;
-                store.push(&v).map_err(Error::Store)?;
-            } else {
-                eprintln!(
-                    "TODO support things which aren't files or directories: {:?}",
-                    dir_entry.file_name()
-                );
+            //a placeholder stands for the large file it replaces, when that file is known,
+            //anything else that reads like one is an ordinary file
+            let placeholder = match large {
+                Some(large) if meta.len() <= large::PLACEHOLDER_MAX => {
+                    match large::read_placeholder(&fs::read(dir_entry.path()).map_err(Error::IO)?) {
+                        Some((content, size)) if large.knows(content).map_err(Error::IO)? => Some((content, size)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            let mut obj_file = File::options()
+                .read(true)
+                .open(dir_entry.path())
+                .map_err(Error::IO)?;
+            let name = dir_entry.file_name().to_string_lossy().to_string();
+            let mode = FileMode::of(&meta);
+            let entry = match (placeholder, large, &config.chunking) {
+                (Some((content, size)), _, _) => DirectoryEntry::Large {
+                    content,
+                    size,
+                    mode,
+                },
+                (None, Some(large), _) if large.matches(&name, meta.len()) => DirectoryEntry::Large {
+                    content: large.push(&mut obj_file).map_err(Error::IO)?,
+                    size: meta.len(),
+                    mode,
+                },
+                (None, _, Some(chunking)) if meta.len() >= chunking.min_file_size => {
+                    push_chunked(store, &mut obj_file, chunking, config.encoding, mode)?
+                }
+                _ => DirectoryEntry::File {
+                    content: store.push_reader(&mut obj_file).map_err(Error::Store)?,
+                    mode,
+                    size: Some(meta.len()),
+                },
+            };
+            if let Some(index) = index {
+                index.record(path, &meta, &entry);
             }
+            Ok(Some(entry))
+        } else if file_type.is_symlink() {
+            //recorded as it is, whether it leads inside the work tree or not
+            let target = fs::read_link(dir_entry.path()).map_err(Error::IO)?;
+            match target.into_os_string().into_string() {
+                Ok(target) => Ok(Some(DirectoryEntry::Symlink { target })),
+                Err(_) => {
+                    eprintln!("{} links to a path that isn't UTF-8, skipped", path);
+                    Ok(None)
+                }
+            }
+        } else {
+            eprintln!("{} is not a file, directory or symlink, skipped", path);
+            Ok(None)
         }
-        Ok(Directory { root })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::memory::InMemoryBlobStore;
+
+    fn file(store: &InMemoryBlobStore, content: &str) -> DirectoryEntry {
+        DirectoryEntry::File {
+            content: store.push(content.as_bytes()).unwrap(),
+            mode: FileMode::Regular,
+            size: Some(content.len() as u64),
+        }
+    }
+
+    fn directory(entries: Vec<(&str, DirectoryEntry)>) -> Directory {
+        Directory {
+            root: entries
+                .into_iter()
+                .map(|(name, entry)| (name.to_string(), entry))
+                .collect(),
+        }
+    }
+
+    //stored apart, the way snapshots keep subdirectories
+    fn tree(store: &InMemoryBlobStore, entries: Vec<(&str, DirectoryEntry)>) -> DirectoryEntry {
+        let tree = encoding::encode(&directory(entries), ObjectEncoding::Binary).unwrap();
+        DirectoryEntry::Tree(store.push_typed(ObjectKind::Tree, &tree).unwrap())
+    }
+
+    fn nested(entries: Vec<(&str, DirectoryEntry)>) -> DirectoryEntry {
+        DirectoryEntry::Directory(Box::new(directory(entries)))
+    }
+
+    fn names(entries: &BTreeMap<String, DirectoryEntry>) -> Vec<&str> {
+        entries.keys().map(String::as_str).collect()
+    }
+
+    //a binary tree with the entries in the given order, as another tool might write it
+    fn raw_tree(entries: &[(&str, &DirectoryEntry)]) -> Vec<u8> {
+        let mut out = encoding::to_binary(&directory(vec![]));
+        out.pop();
+        write_varint(&mut out, entries.len() as u64);
+        for (name, entry) in entries {
+            write_bytes(&mut out, name.as_bytes());
+            entry.write(&mut out);
+        }
+        out
+    }
+
+    fn nested_to(depth: usize) -> Directory {
+        let mut tree = directory(vec![]);
+        for _ in 0..depth {
+            tree = directory(vec![("d", DirectoryEntry::Directory(Box::new(tree)))]);
+        }
+        tree
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        let store = InMemoryBlobStore::new();
+        let main = directory(vec![
+            ("a", file(&store, "a")),
+            ("b", nested(vec![("c", file(&store, "c"))])),
+            ("d", tree(&store, vec![])),
+        ]);
+        let bytes = encoding::to_binary(&main);
+        assert_eq!(encoding::from_binary::<Directory>(&bytes).unwrap(), main);
+    }
+
+    #[test]
+    fn tree_names_have_to_increase() {
+        let store = InMemoryBlobStore::new();
+        let entry = file(&store, "a");
+        let ordered = raw_tree(&[("a", &entry), ("b", &entry)]);
+        assert!(encoding::from_binary::<Directory>(&ordered).is_ok());
+        let unordered = raw_tree(&[("b", &entry), ("a", &entry)]);
+        assert!(encoding::from_binary::<Directory>(&unordered).is_err());
+        let repeated = raw_tree(&[("a", &entry), ("a", &entry)]);
+        assert!(encoding::from_binary::<Directory>(&repeated).is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let deepest = encoding::to_binary(&nested_to(MAX_NESTING));
+        assert!(encoding::from_binary::<Directory>(&deepest).is_ok());
+        let deeper = encoding::to_binary(&nested_to(MAX_NESTING + 1));
+        assert!(encoding::from_binary::<Directory>(&deeper).is_err());
+    }
+
+    #[test]
+    fn identical_trees_have_no_changes() {
+        let store = InMemoryBlobStore::new();
+        let main = directory(vec![
+            ("a", file(&store, "a")),
+            ("sub", tree(&store, vec![("b", file(&store, "b"))])),
+            ("old", nested(vec![("c", file(&store, "c"))])),
+        ]);
+        let diff = main.diff(&main.clone(), &store).unwrap();
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn subtrees_with_the_same_id_are_not_read() {
+        let store = InMemoryBlobStore::new();
+        let missing = DirectoryEntry::Tree(store.id_of(b"not stored"));
+        let main = directory(vec![("sub", missing.clone()), ("a", file(&store, "a"))]);
+        let other = directory(vec![("sub", missing), ("a", file(&store, "changed"))]);
+        let diff = main.diff(&other, &store).unwrap();
+        assert_eq!(diff.modified.keys().collect::<Vec<_>>(), vec!["a"]);
+    }
+
+    #[test]
+    fn nested_and_stored_subtrees_compare_by_content() {
+        let store = InMemoryBlobStore::new();
+        let main = directory(vec![("sub", nested(vec![("b", file(&store, "b"))]))]);
+        let other = directory(vec![("sub", tree(&store, vec![("b", file(&store, "b"))]))]);
+        assert!(main.diff(&other, &store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn changes_inside_a_subtree_are_nested_under_its_name() {
+        let store = InMemoryBlobStore::new();
+        let unchanged = tree(&store, vec![("same", file(&store, "same"))]);
+        let main = directory(vec![
+            ("kept", unchanged.clone()),
+            (
+                "sub",
+                tree(
+                    &store,
+                    vec![
+                        ("changed", file(&store, "1")),
+                        ("deleted", file(&store, "d")),
+                        ("same", file(&store, "s")),
+                    ],
+                ),
+            ),
+        ]);
+        let other = directory(vec![
+            ("kept", unchanged),
+            (
+                "sub",
+                tree(
+                    &store,
+                    vec![
+                        ("added", file(&store, "a")),
+                        ("changed", file(&store, "2")),
+                        ("same", file(&store, "s")),
+                    ],
+                ),
+            ),
+        ]);
+        let diff = main.diff(&other, &store).unwrap();
+        assert!(diff.added.is_empty() && diff.deleted.is_empty());
+        assert_eq!(diff.modified.len(), 1);
+        let Some(DiffEntry::Directory(sub)) = diff.modified.get("sub") else {
+            panic!("sub should be a changed directory: {:?}", diff);
+        };
+        assert_eq!(names(&sub.added), vec!["added"]);
+        assert_eq!(names(&sub.deleted), vec!["deleted"]);
+        assert_eq!(sub.modified.len(), 1);
+        assert_eq!(
+            sub.modified.get("changed"),
+            Some(&DiffEntry::File(file(&store, "2")))
+        );
+    }
+
+    #[test]
+    fn type_changes_are_reported_as_such() {
+        let store = InMemoryBlobStore::new();
+        let as_file = file(&store, "x");
+        let as_dir = tree(&store, vec![("inside", file(&store, "y"))]);
+        let main = directory(vec![("a", as_file.clone()), ("b", as_dir.clone())]);
+        let other = directory(vec![("a", as_dir.clone()), ("b", as_file.clone())]);
+        let diff = main.diff(&other, &store).unwrap();
+        assert!(diff.added.is_empty() && diff.deleted.is_empty());
+        assert_eq!(
+            diff.modified.get("a"),
+            Some(&DiffEntry::TypeChange {
+                from: as_file.clone(),
+                to: as_dir.clone(),
+            })
+        );
+        assert_eq!(
+            diff.modified.get("b"),
+            Some(&DiffEntry::TypeChange {
+                from: as_dir,
+                to: as_file,
+            })
+        );
+    }
+
+    #[test]
+    fn leaves_compare_by_content_mode_and_target() {
+        let store = InMemoryBlobStore::new();
+        let plain = file(&store, "x");
+        let chunked = DirectoryEntry::Chunked {
+            content: plain.content().unwrap(),
+            manifest: store.id_of(b"manifest"),
+            mode: FileMode::Regular,
+        };
+        let executable = DirectoryEntry::File {
+            content: plain.content().unwrap(),
+            mode: FileMode::Executable,
+            size: None,
+        };
+        let link = |target: &str| DirectoryEntry::Symlink {
+            target: target.to_string(),
+        };
+        let main = directory(vec![
+            ("chunked", plain.clone()),
+            ("mode", plain.clone()),
+            ("link", link("a")),
+            ("same_link", link("a")),
+            ("to_link", plain.clone()),
+        ]);
+        let other = directory(vec![
+            ("chunked", chunked),
+            ("mode", executable.clone()),
+            ("link", link("b")),
+            ("same_link", link("a")),
+            ("to_link", link("x")),
+        ]);
+        let diff = main.diff(&other, &store).unwrap();
+        assert_eq!(
+            diff.modified.keys().collect::<Vec<_>>(),
+            vec!["link", "mode", "to_link"]
+        );
+        assert_eq!(diff.modified.get("mode"), Some(&DiffEntry::File(executable)));
+        assert_eq!(diff.modified.get("to_link"), Some(&DiffEntry::File(link("x"))));
+    }
+
+    #[test]
+    fn added_and_deleted_subtrees_are_whole_entries() {
+        let store = InMemoryBlobStore::new();
+        let sub = tree(&store, vec![("b", file(&store, "b"))]);
+        let main = directory(vec![("old", sub.clone())]);
+        let other = directory(vec![("new", sub.clone())]);
+        let diff = main.diff(&other, &store).unwrap();
+        assert_eq!(diff.deleted.get("old"), Some(&sub));
+        assert_eq!(diff.added.get("new"), Some(&sub));
+        assert!(diff.modified.is_empty());
+    }
+
+    #[test]
+    fn missing_subtrees_are_errors() {
+        let store = InMemoryBlobStore::new();
+        let missing = store.id_of(b"not stored");
+        let main = directory(vec![("sub", tree(&store, vec![]))]);
+        let other = directory(vec![("sub", DirectoryEntry::Tree(missing))]);
+        assert!(matches!(
+            main.diff(&other, &store),
+            Err(Error::ObjectMissing(id)) if id == missing
+        ));
     }
 }
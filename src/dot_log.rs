@@ -1,17 +1,29 @@
 use derive_more::From;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeSet,
-    fs::{create_dir, create_dir_all, read_to_string, File},
-    io::Write,
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir, create_dir_all, read_dir, read_to_string, File},
+    io::ErrorKind,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use crate::{
+    atomic::write_atomic,
     blob::Blob,
     commit::Commit,
+    config::{Config, ObjectEncoding, ObjectStore},
     directory::{Directory, Ignores},
-    objects::{directory::DirectoryObjects, Objects},
+    encoding::{self, Canonical},
+    format::{Format, Problem},
+    index::StatIndex,
+    large::LargeFiles,
+    objects::{
+        alternates::AlternateObjects, append::AppendOnlyObjects, cache::CachedObjects, directory::DirectoryObjects,
+        encrypted::{self, EncryptedObjects}, BoxedObjects,
+        ObjectKind, Objects, TypedObject,
+    },
+    revision,
 };
 
 #[derive(Debug, From)]
@@ -21,6 +33,26 @@ pub enum Error {
     #[from]
     Serde(serde_json::Error),
     MissingObject(Blob),
+    //the object exists but was stored as a different kind
+    WrongKind {
+        id: Blob,
+        expected: ObjectKind,
+        found: ObjectKind,
+    },
+    //the repository can't be used by this version, see format
+    Format(Problem),
+    //a revision that doesn't name a commit here, see revision
+    Revision(revision::Problem),
+    //a commit that is its own ancestor, fsck lists these
+    Cycle(Blob),
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpgradeReport {
+    pub from: u32,
+    pub to: u32,
+    //files written by the upgrade
+    pub written: Vec<String>,
 }
 
 pub struct DotLog {
@@ -28,42 +60,65 @@ pub struct DotLog {
 }
 
 impl DotLog {
-    pub fn init(root: PathBuf) -> Result<Self, Error> {
+    pub fn init(root: PathBuf, config: &Config) -> Result<Self, Error> {
         if root.exists() {
             println!("A repository already exists here!");
             return Ok(DotLog { root });
         }
 
+        //so that gc in the alternates keeps what this repository borrows. done
+        //first, an alternate that can't record it fails init before anything exists,
+        //and gc skips borrowers that don't exist if init fails later
+        for path in &config.alternates {
+            if let Some(alternate) = DotLog::is_log_repo(root.join(path)) {
+                alternate.add_borrower(&root)?;
+            }
+        }
+
         create_dir_all(&root)?;
+        write_json(&Format::current(config), &root.join("format"))?;
 
         //set default branch (master)
-        let mut file = File::options()
-            .create(true)
-            .write(true)
-            .open(root.join("branch"))?;
-        file.write_all("master".as_bytes())?;
+        write_atomic(&root.join("branch"), "master".as_bytes())?;
 
         // Create the branches directory
         create_dir(root.join("branches"))?;
 
         //initial commit
-        let mut objects = DirectoryObjects::new(root.clone())?;
+        let objects = open_objects(&root, config)?;
         let blob_dir = Directory::default();
-        let blob_dir = objects.insert_json(&blob_dir)?;
+        let blob_dir = objects.insert_encoded(&blob_dir, config.encoding)?;
         let commit = Commit {
             directory: blob_dir,
             message: String::from("first commit"),
-            previous: BTreeSet::new(),
+            previous: Vec::new(),
         };
 
-        let commit_id = objects.insert_json(&commit)?;
+        let commit_id = objects.insert_encoded(&commit, config.encoding)?;
         write_json(&commit_id, &root.join("branches").join("master"))?;
         let ignores = Ignores::default();
         write_json(&ignores, &root.join("ignores"))?;
+        write_json(config, &root.join("config"))?;
 
         Ok(DotLog { root })
     }
 
+    //the repository of the work tree `dir` is in, looking in its parents too
+    pub fn discover(dir: &Path) -> Option<Self> {
+        dir.ancestors()
+            .map(|dir| dir.join(".log"))
+            .find(|root| root.is_dir())
+            .map(|root| DotLog { root })
+    }
+
+    //folder holding .log, which tree paths start from
+    pub fn work_tree(&self) -> PathBuf {
+        self.root
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
     pub fn is_log_repo(path: PathBuf) -> Option<Self> {
         if path.exists() {
             Some(DotLog { root: path })
@@ -77,16 +132,115 @@ impl DotLog {
     }
 
     pub fn set_branch(&self, new_branch: &str) -> Result<(), Error> {
-        let mut file = File::options()
+        Ok(write_atomic(&self.root.join("branch"), new_branch.as_bytes())?)
+    }
+
+    pub fn get_objects(&self) -> Result<CachedObjects<BoxedObjects>, Error> {
+        let config = self.config()?;
+        let mut objects = open_objects(&self.root, &config)?;
+        let alternates = self.get_alternates(&config)?;
+        if !alternates.is_empty() {
+            objects = Box::new(AlternateObjects::new(objects, alternates));
+        }
+        Ok(CachedObjects::new(objects, config.cache.budget))
+    }
+
+    //objects of this repository only, without the alternates or the cache
+    pub fn get_local_objects(&self) -> Result<BoxedObjects, Error> {
+        open_objects(&self.root, &self.config()?)
+    }
+
+    //stores of the alternates, their own alternates are not followed
+    fn get_alternates(&self, config: &Config) -> Result<Vec<BoxedObjects>, Error> {
+        let mut alternates = Vec::new();
+        for path in &config.alternates {
+            let alternate = match DotLog::is_log_repo(self.root.join(path)) {
+                Some(alternate) => alternate,
+                None => {
+                    return Err(Error::IO(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("alternate repository {} is missing", path.display()),
+                    )))
+                }
+            };
+            let alternate_config = alternate.config()?;
+            same_hash(config, &alternate_config, path)?;
+            alternates.push(open_objects(&alternate.root, &alternate_config)?);
+        }
+        Ok(alternates)
+    }
+
+    //.log folders of the repositories borrowing objects from this one, recorded by their init
+    pub fn borrowers(&self) -> Result<Vec<PathBuf>, Error> {
+        let path = self.root.join("borrowers");
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        read_json(&path)
+    }
+
+    //borrowers.lock makes concurrent inits take turns, so no borrower is lost
+    fn add_borrower(&self, borrower: &Path) -> Result<(), Error> {
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
             .write(true)
-            .truncate(true)
-            .open(self.root.join("branch"))?;
-        file.write_all(new_branch.as_bytes())?;
+            .open(self.root.join("borrowers.lock"))?;
+        lock.lock()?;
+        let mut borrowers = self.borrowers()?;
+        if !borrowers.iter().any(|path| path == borrower) {
+            borrowers.push(borrower.to_path_buf());
+            write_json(&borrowers, &self.root.join("borrowers"))?;
+        }
         Ok(())
     }
 
-    pub fn get_objects(&self) -> Result<DirectoryObjects, Error> {
-        Ok(DirectoryObjects::new(self.root.clone())?)
+    //None when large files are not kept apart
+    pub fn large_files(&self, config: &Config) -> Result<Option<LargeFiles>, Error> {
+        let large_files = match &config.large_files {
+            Some(large_files) => large_files.clone(),
+            None => return Ok(None),
+        };
+        let objects = open_large(&self.root, config)?;
+        let source = match &large_files.source {
+            Some(path) => match DotLog::is_log_repo(self.root.join(path)) {
+                Some(source) => {
+                    let source_config = source.config()?;
+                    same_hash(config, &source_config, path)?;
+                    Some(open_large(&source.root, &source_config)?)
+                }
+                None => {
+                    return Err(Error::IO(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("large file source {} is missing", path.display()),
+                    )))
+                }
+            },
+            None => None,
+        };
+        Ok(Some(LargeFiles::new(large_files, objects, source)))
+    }
+
+    //None when the repository uses another object store
+    pub fn get_directory_objects(&self) -> Result<Option<DirectoryObjects>, Error> {
+        let config = self.config()?;
+        match config.store {
+            ObjectStore::Directory => Ok(Some(DirectoryObjects::new(self.root.clone(), config.hash)?)),
+            _ => Ok(None),
+        }
+    }
+
+    //None when the repository uses another object store
+    pub fn get_append_objects(&self) -> Result<Option<AppendOnlyObjects>, Error> {
+        let config = self.config()?;
+        match config.store {
+            ObjectStore::Append => Ok(Some(AppendOnlyObjects::open(
+                &self.root,
+                config.hash,
+                config.encryption.is_some(),
+            )?)),
+            _ => Ok(None),
+        }
     }
 
     pub fn get_branch_commit_hash(&self, branch: &str) -> Result<Blob, Error> {
@@ -97,6 +251,19 @@ impl DotLog {
         write_json(&blob, &self.root.join("branches").join(branch))
     }
 
+    pub fn branches(&self) -> Result<Vec<String>, Error> {
+        let mut branches = Vec::new();
+        for entry in read_dir(self.root.join("branches"))? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            //skip temporary files of unfinished writes
+            if !name.starts_with('.') {
+                branches.push(name);
+            }
+        }
+        branches.sort();
+        Ok(branches)
+    }
+
     pub fn branch_exists(&self, branch: &str) -> bool {
         self.root.join("branches").join(branch).exists()
     }
@@ -112,25 +279,229 @@ impl DotLog {
     pub fn ignores(&self) -> Result<Ignores, Error> {
         read_json(&self.root.join("ignores"))
     }
+
+    //stat cache of the working directory, empty when missing or corrupt
+    pub fn index(&self, config: &Config) -> StatIndex {
+        StatIndex::load(self.root.join("index"), config)
+    }
+
+    //repositories created before the config file existed use the defaults
+    //fails when this version can't use the repository, every command reads it first
+    pub fn config(&self) -> Result<Config, Error> {
+        self.format()?.check().map_err(Error::Format)?;
+        self.read_config()
+    }
+
+    fn read_config(&self) -> Result<Config, Error> {
+        let path = self.root.join("config");
+        if path.exists() {
+            read_json(&path)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    //repositories from before the format file are version 0
+    pub fn format(&self) -> Result<Format, Error> {
+        let path = self.root.join("format");
+        if path.exists() {
+            read_json(&path)
+        } else {
+            Ok(Format::legacy())
+        }
+    }
+
+    //migrate the repository in place to the current format
+    //every step only adds what is missing, so an interrupted upgrade can run again
+    pub fn upgrade(&self) -> Result<UpgradeReport, Error> {
+        let format = self.format()?;
+        match format.check() {
+            Ok(()) | Err(Problem::TooOld(_)) => {}
+            Err(problem) => return Err(Error::Format(problem)),
+        }
+        let mut written = Vec::new();
+        //version 0: settings were implied, trees and commits were json
+        if format.version < 1 {
+            for (name, missing) in [
+                ("config", serde_json::to_vec_pretty(&Config::default())?),
+                ("ignores", serde_json::to_vec_pretty(&Ignores::default())?),
+            ] {
+                let path = self.root.join(name);
+                if !path.exists() {
+                    write_atomic(&path, &missing)?;
+                    written.push(name.to_string());
+                }
+            }
+        }
+        //version 1: trees were keyed by absolute paths, the whole history is
+        //rewritten, the old objects are left for gc
+        if format.version < 2 {
+            written.extend(self.relativize_history()?);
+        }
+        //version 2: older trees are still read, files just have no size recorded
+        //version 3: symlinks were followed or dropped, there are none to convert
+        //version 4: nested subdirectories are still read, new commits store them apart
+        //version 5: objects keep their ids, only new ones are hashed with their kind
+        let current = Format::current(&self.read_config()?);
+        if current != format {
+            write_json(&current, &self.root.join("format"))?;
+            written.push(String::from("format"));
+        }
+        Ok(UpgradeReport {
+            from: format.version,
+            to: current.version,
+            written,
+        })
+    }
+
+    //rewrite every commit with trees keyed by name, then move the branches to them
+    //migrated trees and commits come out the same, so this can run again
+    fn relativize_history(&self) -> Result<Vec<String>, Error> {
+        let config = self.read_config()?;
+        let mut objects = open_objects(&self.root, &config)?;
+        let alternates = self.get_alternates(&config)?;
+        if !alternates.is_empty() {
+            objects = Box::new(AlternateObjects::new(objects, alternates));
+        }
+        let mut commits: BTreeMap<Blob, Blob> = BTreeMap::new();
+        let mut trees: BTreeMap<Blob, Blob> = BTreeMap::new();
+        let mut written = Vec::new();
+        //commits whose parents are still being rewritten, meeting one again closes a cycle
+        let mut visiting: BTreeSet<Blob> = BTreeSet::new();
+        for branch in self.branches()? {
+            let head = self.get_branch_commit_hash(&branch)?;
+            //parents are rewritten before their children
+            let mut stack = vec![(head, false)];
+            while let Some((id, parents_done)) = stack.pop() {
+                if commits.contains_key(&id) {
+                    continue;
+                }
+                if !parents_done && !visiting.insert(id) {
+                    return Err(Error::Cycle(id));
+                }
+                let commit: Commit = objects.read_json(id)?;
+                if !parents_done {
+                    stack.push((id, true));
+                    stack.extend(commit.previous.iter().map(|parent| (*parent, false)));
+                    continue;
+                }
+                let directory = match trees.get(&commit.directory) {
+                    Some(directory) => *directory,
+                    None => {
+                        let tree: Directory = objects.read_json(commit.directory)?;
+                        let directory = objects.insert_encoded(&tree.into_relative(), config.encoding)?;
+                        trees.insert(commit.directory, directory);
+                        directory
+                    }
+                };
+                let migrated = Commit {
+                    message: commit.message,
+                    directory,
+                    previous: commit.previous.iter().map(|parent| commits[parent]).collect(),
+                };
+                commits.insert(id, objects.insert_encoded(&migrated, config.encoding)?);
+                visiting.remove(&id);
+            }
+            if commits[&head] != head {
+                write_json(&commits[&head], &self.root.join("branches").join(&branch))?;
+                written.push(format!("branches/{}", branch));
+            }
+        }
+        Ok(written)
+    }
 }
 
-//writing and reading json files from /objects
+//writing and reading trees and commits in /objects, tagged with the kind of their type
+//they are stored as json or in the binary encoding, and read_json accepts both
 pub trait JSON {
-    fn insert_json<A: Serialize>(&mut self, thing: &A) -> Result<Blob, Error>;
-    fn read_json<A: for<'de> Deserialize<'de>>(&mut self, object_id: Blob) -> Result<A, Error>;
+    fn insert_json<A: Serialize + TypedObject>(&self, thing: &A) -> Result<Blob, Error>;
+    fn insert_encoded<A: Serialize + Canonical>(
+        &self,
+        thing: &A,
+        encoding: ObjectEncoding,
+    ) -> Result<Blob, Error>;
+    fn read_json<A: for<'de> Deserialize<'de> + Canonical + Clone + Send + Sync + 'static>(
+        &self,
+        object_id: Blob,
+    ) -> Result<A, Error>;
 }
 
-impl JSON for DirectoryObjects {
-    fn insert_json<A: Serialize>(&mut self, thing: &A) -> Result<Blob, Error> {
-        Ok(self.push(&serde_json::to_vec_pretty(thing)?)?)
+impl<O: Objects<Error = std::io::Error> + ?Sized> JSON for O {
+    fn insert_json<A: Serialize + TypedObject>(&self, thing: &A) -> Result<Blob, Error> {
+        Ok(self.push_typed(A::KIND, &serde_json::to_vec_pretty(thing)?)?)
+    }
+
+    fn insert_encoded<A: Serialize + Canonical>(
+        &self,
+        thing: &A,
+        encoding: ObjectEncoding,
+    ) -> Result<Blob, Error> {
+        Ok(self.push_typed(A::KIND, &encoding::encode(thing, encoding)?)?)
     }
 
-    fn read_json<A: for<'de> Deserialize<'de>>(&mut self, object_id: Blob) -> Result<A, Error> {
-        match self.get(object_id)? {
-            None => Err(Error::MissingObject(object_id)),
-            Some(obj) => Ok(serde_json::from_slice(&obj)?),
+    fn read_json<A: for<'de> Deserialize<'de> + Canonical + Clone + Send + Sync + 'static>(
+        &self,
+        object_id: Blob,
+    ) -> Result<A, Error> {
+        //only a value that already passed the kind check is cached as an A
+        if let Some(parsed) = self.get_parsed(object_id) {
+            if let Some(parsed) = parsed.downcast_ref::<A>() {
+                return Ok(parsed.clone());
+            }
         }
+        let (parsed, size): (A, usize) = match self.get_typed(object_id)? {
+            None => return Err(Error::MissingObject(object_id)),
+            Some((Some(found), _)) if found != A::KIND => {
+                return Err(Error::WrongKind {
+                    id: object_id,
+                    expected: A::KIND,
+                    found,
+                })
+            }
+            //objects without a kind predate kinds, they can only be told apart by parsing
+            Some((_, obj)) if encoding::is_binary(&obj) => (encoding::from_binary(&obj)?, obj.len()),
+            Some((_, obj)) => (serde_json::from_slice(&obj)?, obj.len()),
+        };
+        self.put_parsed(object_id, Arc::new(parsed.clone()), size);
+        Ok(parsed)
+    }
+}
+
+fn open_objects(root: &Path, config: &Config) -> Result<BoxedObjects, Error> {
+    let objects: BoxedObjects = match config.store {
+        ObjectStore::Directory => Box::new(DirectoryObjects::new(root.to_path_buf(), config.hash)?),
+        ObjectStore::Append => Box::new(AppendOnlyObjects::open(root, config.hash, config.encryption.is_some())?),
+    };
+    encrypt_objects(root, config, objects)
+}
+
+//large files of the repository at `root`, kept under large/ as a directory store
+fn open_large(root: &Path, config: &Config) -> Result<BoxedObjects, Error> {
+    create_dir_all(root.join("large"))?;
+    encrypt_objects(root, config, Box::new(DirectoryObjects::new(root.join("large"), config.hash)?))
+}
+
+//ids are only shared between repositories hashing with the same algorithm
+fn same_hash(config: &Config, other: &Config, path: &Path) -> Result<(), Error> {
+    if config.hash != other.hash {
+        return Err(Error::IO(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("repository {} uses another hash algorithm", path.display()),
+        )));
     }
+    Ok(())
+}
+
+//wrap `objects` when the repository is encrypted
+fn encrypt_objects(root: &Path, config: &Config, objects: BoxedObjects) -> Result<BoxedObjects, Error> {
+    Ok(match &config.encryption {
+        Some(encryption) => {
+            let key_file = encryption.key_file.as_ref().map(|path| root.join(path));
+            let key = encrypted::load_key(key_file.as_deref(), encryption.key_check)?;
+            Box::new(EncryptedObjects::new(objects, &key, config.hash))
+        }
+        None => objects,
+    })
 }
 
 fn read_json<A: for<'de> Deserialize<'de>>(path: &Path) -> Result<A, Error> {
@@ -140,8 +511,61 @@ fn read_json<A: for<'de> Deserialize<'de>>(path: &Path) -> Result<A, Error> {
 }
 
 fn write_json<A: Serialize>(thing: &A, path: &Path) -> Result<(), Error> {
-    Ok(serde_json::to_writer_pretty(
-        File::options().write(true).create(true).open(path)?,
-        thing,
-    )?)
+    Ok(write_atomic(path, &serde_json::to_vec_pretty(thing)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-dot-log-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn commit(message: &str, directory: Blob, previous: Vec<Blob>) -> Commit {
+        Commit {
+            message: message.to_string(),
+            directory,
+            previous,
+        }
+    }
+
+    #[test]
+    fn relativizes_merges() {
+        let dir = temp_dir("merges");
+        let dot_log = DotLog::init(dir.join(".log"), &Config::default()).unwrap();
+        let objects = dot_log.get_objects().unwrap();
+        let root = dot_log.get_branch_commit_hash("master").unwrap();
+        let tree = objects.read_json::<Commit>(root).unwrap().directory;
+        let a = objects.insert_encoded(&commit("a", tree, vec![root]), ObjectEncoding::Binary).unwrap();
+        let b = objects.insert_encoded(&commit("b", tree, vec![root]), ObjectEncoding::Binary).unwrap();
+        let merge = objects.insert_encoded(&commit("m", tree, vec![a, b]), ObjectEncoding::Binary).unwrap();
+        dot_log.set_branch_commit_hash("master", merge).unwrap();
+        dot_log.relativize_history().unwrap();
+        //the rewritten merge still has both parents, and a second run has nothing left to do
+        let merge = dot_log.get_branch_commit_hash("master").unwrap();
+        assert_eq!(objects.read_json::<Commit>(merge).unwrap().previous.len(), 2);
+        assert_eq!(dot_log.relativize_history().unwrap(), Vec::<String>::new());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_cycles() {
+        let dir = temp_dir("cycles");
+        let dot_log = DotLog::init(dir.join(".log"), &Config::default()).unwrap();
+        let objects = dot_log.get_objects().unwrap();
+        let root = dot_log.get_branch_commit_hash("master").unwrap();
+        let tree = objects.read_json::<Commit>(root).unwrap().directory;
+        //ids can't close a cycle, so one commit is stored under an id chosen before it
+        let forged = Blob::checksum(b"forged");
+        let a = objects.insert_encoded(&commit("a", tree, vec![forged]), ObjectEncoding::Binary).unwrap();
+        let stored = encoding::encode(&commit("b", tree, vec![a]), ObjectEncoding::Binary).unwrap();
+        objects.push_stored(forged, ObjectKind::Commit, &stored).unwrap();
+        dot_log.set_branch_commit_hash("master", a).unwrap();
+        assert!(matches!(dot_log.relativize_history(), Err(Error::Cycle(id)) if id == a));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,213 @@
+//canonical binary encoding of trees, commits and chunk manifests
+//
+//layout: "LOGB", format version, ObjectKind byte, then the fields in a fixed
+//order with varint lengths and raw 32 byte ids. maps are written in key order,
+//so equal content always gives the same bytes and therefore the same Blob.
+use std::io::ErrorKind;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{blob::Blob, config::ObjectEncoding, objects::TypedObject};
+
+const MAGIC: &[u8] = b"LOGB";
+const VERSION: u8 = 1;
+
+pub trait Canonical: TypedObject + Sized {
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(input: &mut Reader) -> Result<Self, std::io::Error>;
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn to_binary<A: Canonical>(thing: &A) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.push(A::KIND.to_byte());
+    thing.write(&mut out);
+    out
+}
+
+pub fn from_binary<A: Canonical>(bytes: &[u8]) -> Result<A, std::io::Error> {
+    let mut input = Reader::new(bytes);
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a binary object"));
+    }
+    if input.byte()? != VERSION {
+        return Err(invalid("unknown binary object version"));
+    }
+    if input.byte()? != A::KIND.to_byte() {
+        return Err(invalid("binary object of the wrong kind"));
+    }
+    let thing = A::read(&mut input)?;
+    if !input.is_at_end() {
+        return Err(invalid("trailing bytes after binary object"));
+    }
+    Ok(thing)
+}
+
+pub fn encode<A: Serialize + Canonical>(
+    thing: &A,
+    encoding: ObjectEncoding,
+) -> Result<Vec<u8>, serde_json::Error> {
+    match encoding {
+        ObjectEncoding::Json => serde_json::to_vec_pretty(thing),
+        ObjectEncoding::Binary => Ok(to_binary(thing)),
+    }
+}
+
+//either encoding, told apart by the binary header
+pub fn decode<A: DeserializeOwned + Canonical>(bytes: &[u8]) -> Result<A, std::io::Error> {
+    if is_binary(bytes) {
+        from_binary(bytes)
+    } else {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+//only the shortest encoding of a value is accepted, so that a value has one encoding
+pub fn read_varint(input: &[u8], pos: &mut usize) -> Result<u64, std::io::Error> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *input.get(*pos).ok_or_else(|| invalid("truncated varint"))?;
+        *pos += 1;
+        if shift > 63 || (shift == 63 && byte & 0x7f > 1) {
+            return Err(invalid("varint is too long"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            //a last group of zeros could have been left out
+            if byte == 0 && shift > 0 {
+                return Err(invalid("varint is not minimal"));
+            }
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+pub fn write_blob(out: &mut Vec<u8>, blob: &Blob) {
+    out.extend_from_slice(blob.as_bytes());
+}
+
+pub struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Reader { input, pos: 0 }
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.input.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], std::io::Error> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.input.len())
+            .ok_or_else(|| invalid("truncated binary object"))?;
+        let bytes = &self.input[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, std::io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn varint(&mut self) -> Result<u64, std::io::Error> {
+        read_varint(self.input, &mut self.pos)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], std::io::Error> {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> Result<String, std::io::Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("string is not utf-8"))
+    }
+
+    pub fn blob(&mut self) -> Result<Blob, std::io::Error> {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(self.take(32)?);
+        Ok(Blob::from_bytes(bytes))
+    }
+}
+
+pub fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(bytes: &[u8]) -> Result<u64, std::io::Error> {
+        let mut pos = 0;
+        let value = read_varint(bytes, &mut pos)?;
+        assert_eq!(pos, bytes.len());
+        Ok(value)
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(varint(&out).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn longer_varints_are_rejected() {
+        //1 and 0 with a trailing group of zeros
+        assert!(varint(&[0x81, 0x00]).is_err());
+        assert!(varint(&[0x80, 0x00]).is_err());
+        assert!(varint(&[0xff, 0x80, 0x00]).is_err());
+    }
+
+    #[test]
+    fn overflowing_varints_are_rejected() {
+        let mut max = Vec::new();
+        write_varint(&mut max, u64::MAX);
+        assert_eq!(max.len(), 10);
+        //the tenth group only has room for one bit
+        let mut over = max.clone();
+        over[9] = 0x02;
+        assert!(varint(&over).is_err());
+        let mut longer = max;
+        longer[9] |= 0x80;
+        longer.push(0x01);
+        assert!(varint(&longer).is_err());
+    }
+
+    #[test]
+    fn truncated_varints_are_rejected() {
+        assert!(varint(&[]).is_err());
+        assert!(varint(&[0x80]).is_err());
+    }
+}
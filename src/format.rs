@@ -0,0 +1,96 @@
+//on-disk format of a repository, stored as json in .log/format
+//
+//the version changes whenever the layout changes in a way older versions can't
+//read, features list the settings in use that a reader must understand.
+//repositories from before this file are version 0, `upgrade` migrates them
+//
+//version 1 trees keyed entries by the path they were snapshotted from, mostly
+//absolute, version 2 keys them by name, see Directory. version 3 adds the mode
+//and size of files to tree entries, which older versions can't read. version 4
+//records symlinks as entries of their own. version 5 stores subdirectories as
+//tree objects of their own instead of nesting them. version 6 hashes the kind of
+//trees, commits and manifests into their id, older versions take such objects
+//for corrupt
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, HashAlgorithm, ObjectEncoding, ObjectStore};
+
+//version written by init and upgrade, older versions have to be upgraded first
+pub const VERSION: u32 = 6;
+
+pub const FEATURES: &[&str] = &[
+    "chunking",
+    "binary-encoding",
+    "append-store",
+    "alternates",
+    "encryption",
+    "large-files",
+    "sha256",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Format {
+    pub version: u32,
+    pub features: BTreeSet<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    //has to be upgraded before it can be used
+    TooOld(u32),
+    //written by a newer version of the program
+    TooNew(u32),
+    UnknownFeatures(Vec<String>),
+}
+
+impl Format {
+    //format of a repository created now with `config`
+    pub fn current(config: &Config) -> Self {
+        let mut features = BTreeSet::new();
+        let mut add = |used: bool, feature: &str| {
+            if used {
+                features.insert(feature.to_string());
+            }
+        };
+        add(config.chunking.is_some(), "chunking");
+        add(config.encoding == ObjectEncoding::Binary, "binary-encoding");
+        add(config.store == ObjectStore::Append, "append-store");
+        add(!config.alternates.is_empty(), "alternates");
+        add(config.encryption.is_some(), "encryption");
+        add(config.large_files.is_some(), "large-files");
+        add(config.hash == HashAlgorithm::Sha256, "sha256");
+        Format {
+            version: VERSION,
+            features,
+        }
+    }
+
+    //format of repositories without a format file
+    pub fn legacy() -> Self {
+        Format {
+            version: 0,
+            features: BTreeSet::new(),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Problem> {
+        if self.version > VERSION {
+            return Err(Problem::TooNew(self.version));
+        }
+        if self.version < VERSION {
+            return Err(Problem::TooOld(self.version));
+        }
+        let unknown: Vec<String> = self
+            .features
+            .iter()
+            .filter(|feature| !FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            return Err(Problem::UnknownFeatures(unknown));
+        }
+        Ok(())
+    }
+}
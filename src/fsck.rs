@@ -0,0 +1,249 @@
+//integrity check of the objects, the commit graph and the branches
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+
+use crate::{
+    blob::Blob,
+    chunker::ChunkManifest,
+    commit::Commit,
+    directory::{Directory, DirectoryEntry},
+    dot_log::{DotLog, Error, JSON},
+    objects::{ObjectKind, Objects},
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    pub objects_checked: usize,
+    pub corrupt_objects: Vec<CorruptObject>,
+    pub missing_objects: Vec<MissingObject>,
+    pub bad_branches: Vec<BadBranch>,
+    //commits which are their own ancestor
+    pub cycles: Vec<Blob>,
+    //repositories borrowing objects from this one, gc here keeps what they reach
+    pub borrowers: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorruptObject {
+    pub id: Blob,
+    pub problem: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MissingObject {
+    pub id: Blob,
+    //commit or tree which references the missing object
+    pub referenced_by: Blob,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BadBranch {
+    pub branch: String,
+    pub problem: String,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_objects.is_empty()
+            && self.missing_objects.is_empty()
+            && self.bad_branches.is_empty()
+            && self.cycles.is_empty()
+    }
+}
+
+enum Visit {
+    InProgress,
+    Done,
+}
+
+//the objects of this repository are rehashed, history is read through `objects`,
+//which also sees the alternates
+pub fn fsck<L: Objects<Error = std::io::Error> + ?Sized, O: Objects<Error = std::io::Error> + ?Sized>(
+    dot_log: &DotLog,
+    local: &L,
+    objects: &O,
+) -> Result<FsckReport, Error> {
+    let mut report = FsckReport::default();
+    check_objects(local, &mut report)?;
+    report.borrowers = dot_log.borrowers()?;
+
+    let mut heads = Vec::new();
+    for branch in dot_log.branches()? {
+        match dot_log.get_branch_commit_hash(&branch) {
+            Ok(id) => heads.push((branch, id)),
+            Err(err) => report.bad_branches.push(BadBranch {
+                branch,
+                problem: format!("{:?}", err),
+            }),
+        }
+    }
+    let current = dot_log.get_branch()?;
+    if !dot_log.branch_exists(&current) {
+        report.bad_branches.push(BadBranch {
+            branch: current,
+            problem: String::from("current branch does not exist"),
+        });
+    }
+
+    //depth first walk of the commit graph, a commit met again while in progress closes a cycle
+    let mut visits: BTreeMap<Blob, Visit> = BTreeMap::new();
+    let mut checked_trees = BTreeSet::new();
+    for (branch, head) in heads {
+        if !objects.exists(head)? {
+            report.bad_branches.push(BadBranch {
+                branch,
+                problem: String::from("branch points to a missing commit"),
+            });
+            continue;
+        }
+        let mut stack = vec![(head, false)];
+        while let Some((id, finished)) = stack.pop() {
+            if finished {
+                visits.insert(id, Visit::Done);
+                continue;
+            }
+            if visits.contains_key(&id) {
+                continue;
+            }
+            visits.insert(id, Visit::InProgress);
+            stack.push((id, true));
+
+            let commit: Commit = match objects.read_json(id) {
+                Ok(commit) => commit,
+                Err(err) => {
+                    report.corrupt_objects.push(CorruptObject {
+                        id,
+                        problem: format!("unreadable commit: {:?}", err),
+                    });
+                    continue;
+                }
+            };
+            for parent in commit.previous {
+                match visits.get(&parent) {
+                    Some(Visit::InProgress) => report.cycles.push(parent),
+                    Some(Visit::Done) => {}
+                    None => {
+                        if objects.exists(parent)? {
+                            stack.push((parent, false));
+                        } else {
+                            report.missing_objects.push(MissingObject {
+                                id: parent,
+                                referenced_by: id,
+                            });
+                        }
+                    }
+                }
+            }
+            check_tree(objects, commit.directory, id, &mut checked_trees, &mut report)?;
+        }
+    }
+    Ok(report)
+}
+
+//rehash every object of the store against its id
+fn check_objects<L: Objects<Error = std::io::Error> + ?Sized>(
+    local: &L,
+    report: &mut FsckReport,
+) -> Result<(), Error> {
+    for id in local.ids()? {
+        report.objects_checked += 1;
+        let problem = match local.get_typed(id) {
+            Ok(Some((kind, v))) if local.is_id_of(id, kind, &v) => continue,
+            Ok(Some((kind, v))) => format!(
+                "object hashes to {}",
+                local.id_of_typed(kind.unwrap_or(ObjectKind::Blob), &v)
+            ),
+            Ok(None) => String::from("object disappeared"),
+            Err(err) => format!("unreadable object: {}", err),
+        };
+        report.corrupt_objects.push(CorruptObject { id, problem });
+    }
+    Ok(())
+}
+
+//trees shared between commits, or by subdirectories, are checked once
+fn check_tree<O: Objects<Error = std::io::Error> + ?Sized>(
+    objects: &O,
+    tree_id: Blob,
+    referenced_by: Blob,
+    checked_trees: &mut BTreeSet<Blob>,
+    report: &mut FsckReport,
+) -> Result<(), Error> {
+    if !checked_trees.insert(tree_id) {
+        return Ok(());
+    }
+    if !objects.exists(tree_id)? {
+        report.missing_objects.push(MissingObject {
+            id: tree_id,
+            referenced_by,
+        });
+        return Ok(());
+    }
+    match objects.read_json::<Directory>(tree_id) {
+        Ok(tree) => check_entries(objects, &tree, tree_id, checked_trees, report),
+        Err(err) => {
+            report.corrupt_objects.push(CorruptObject {
+                id: tree_id,
+                problem: format!("unreadable tree: {:?}", err),
+            });
+            Ok(())
+        }
+    }
+}
+
+fn check_entries<O: Objects<Error = std::io::Error> + ?Sized>(
+    objects: &O,
+    tree: &Directory,
+    tree_id: Blob,
+    checked_trees: &mut BTreeSet<Blob>,
+    report: &mut FsckReport,
+) -> Result<(), Error> {
+    for (_, entry) in tree.entries() {
+        match entry {
+            DirectoryEntry::File { content, .. } => {
+                if !objects.exists(*content)? {
+                    report.missing_objects.push(MissingObject {
+                        id: *content,
+                        referenced_by: tree_id,
+                    });
+                }
+            }
+            DirectoryEntry::Directory(dir) => check_entries(objects, dir, tree_id, checked_trees, report)?,
+            DirectoryEntry::Tree(tree) => check_tree(objects, *tree, tree_id, checked_trees, report)?,
+            DirectoryEntry::Chunked { manifest, .. } => {
+                if !objects.exists(*manifest)? {
+                    report.missing_objects.push(MissingObject {
+                        id: *manifest,
+                        referenced_by: tree_id,
+                    });
+                    continue;
+                }
+                match objects.read_json::<ChunkManifest>(*manifest) {
+                    Ok(chunks) => {
+                        for chunk in chunks.chunks {
+                            if !objects.exists(chunk)? {
+                                report.missing_objects.push(MissingObject {
+                                    id: chunk,
+                                    referenced_by: *manifest,
+                                });
+                            }
+                        }
+                    }
+                    Err(err) => report.corrupt_objects.push(CorruptObject {
+                        id: *manifest,
+                        problem: format!("unreadable chunk list: {:?}", err),
+                    }),
+                }
+            }
+            //the large file store may be pruned, missing content is not an error
+            DirectoryEntry::Large { .. } => {}
+            //the target is kept in the tree, where it leads isn't checked
+            DirectoryEntry::Symlink { .. } => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//garbage collection of objects that no branch can reach
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{
+    blob::Blob,
+    chunker::ChunkManifest,
+    commit::Commit,
+    directory::{Directory, DirectoryEntry},
+    dot_log::{DotLog, Error, JSON},
+    objects::Objects,
+};
+
+#[derive(Debug, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub reachable: usize,
+    //unreachable objects older than the grace period, deleted unless dry_run
+    pub pruned: Vec<Blob>,
+    //unreachable objects younger than the grace period, always kept
+    pub recent: Vec<Blob>,
+    //unreachable objects whose store doesn't record when they were written, kept
+    pub unknown_age: Vec<Blob>,
+    //repositories borrowing objects from this one, what they reach is kept too
+    pub borrowers: Vec<PathBuf>,
+}
+
+//every commit, tree and file blob reachable from a branch
+pub fn reachable<O: JSON + ?Sized>(
+    dot_log: &DotLog,
+    objects: &O,
+) -> Result<BTreeSet<Blob>, Error> {
+    let mut reachable = BTreeSet::new();
+    let mut commits = Vec::new();
+    for branch in dot_log.branches()? {
+        commits.push(dot_log.get_branch_commit_hash(&branch)?);
+    }
+
+    while let Some(commit_id) = commits.pop() {
+        if !reachable.insert(commit_id) {
+            continue;
+        }
+        let commit: Commit = objects.read_json(commit_id)?;
+        commits.extend(commit.previous);
+        if reachable.insert(commit.directory) {
+            let tree: Directory = objects.read_json(commit.directory)?;
+            mark_tree(objects, &tree, &mut reachable)?;
+        }
+    }
+    Ok(reachable)
+}
+
+//objects reachable here or from a repository borrowing objects from this one,
+//and the borrowers that were read. borrowers that no longer exist are skipped
+pub fn reachable_with_borrowers<O: JSON + ?Sized>(
+    dot_log: &DotLog,
+    objects: &O,
+) -> Result<(BTreeSet<Blob>, Vec<PathBuf>), Error> {
+    let mut keep = reachable(dot_log, objects)?;
+    let mut borrowers = Vec::new();
+    for path in dot_log.borrowers()? {
+        if let Some(borrower) = DotLog::is_log_repo(path.clone()) {
+            //the borrower reads the objects of this repository through its alternates
+            keep.extend(reachable(&borrower, &borrower.get_objects()?)?);
+            borrowers.push(path);
+        }
+    }
+    Ok((keep, borrowers))
+}
+
+fn mark_tree<O: JSON + ?Sized>(
+    objects: &O,
+    tree: &Directory,
+    reachable: &mut BTreeSet<Blob>,
+) -> Result<(), Error> {
+    for (_, entry) in tree.entries() {
+        match entry {
+            DirectoryEntry::File { content, .. } => {
+                reachable.insert(*content);
+            }
+            DirectoryEntry::Directory(dir) => mark_tree(objects, dir, reachable)?,
+            //subtrees shared with a tree already marked are marked already
+            DirectoryEntry::Tree(tree) => {
+                if reachable.insert(*tree) {
+                    let tree: Directory = objects.read_json(*tree)?;
+                    mark_tree(objects, &tree, reachable)?;
+                }
+            }
+            DirectoryEntry::Chunked { manifest, .. } => {
+                if reachable.insert(*manifest) {
+                    let manifest: ChunkManifest = objects.read_json(*manifest)?;
+                    reachable.extend(manifest.chunks);
+                }
+            }
+            //kept in the large file store, which is pruned on its own
+            DirectoryEntry::Large { .. } => {}
+            DirectoryEntry::Symlink { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+//prune unreachable objects that were written more than `grace` ago
+//the grace period protects objects of a commit that is still being written,
+//so objects of stores that don't record when they were written are never pruned
+//history is read through `reader`, which also sees the alternates, only `objects` is pruned
+pub fn gc<S: Objects<Error = std::io::Error> + ?Sized, O: JSON + ?Sized>(
+    dot_log: &DotLog,
+    objects: &S,
+    reader: &O,
+    grace: Duration,
+    dry_run: bool,
+) -> Result<GcReport, Error> {
+    let (reachable, borrowers) = reachable_with_borrowers(dot_log, reader)?;
+
+    let now = SystemTime::now();
+    let mut pruned = Vec::new();
+    let mut recent = Vec::new();
+    let mut unknown_age = Vec::new();
+    for id in objects.ids()? {
+        if reachable.contains(&id) {
+            continue;
+        }
+        let age = match objects.modified(id)? {
+            Some(modified) => now.duration_since(modified).unwrap_or_default(),
+            None => {
+                unknown_age.push(id);
+                continue;
+            }
+        };
+        if age >= grace {
+            pruned.push(id);
+        } else {
+            recent.push(id);
+        }
+    }
+
+    if !dry_run {
+        objects.delete(&pruned.iter().copied().collect())?;
+    }
+
+    Ok(GcReport {
+        dry_run,
+        reachable: reachable.len(),
+        pruned,
+        recent,
+        unknown_age,
+        borrowers,
+    })
+}
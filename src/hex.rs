@@ -33,7 +33,7 @@ impl Display for Hex {
     }
 }
 
-impl<'a> From<&'a [u8]> for Hex {
+impl From<&[u8]> for Hex {
     fn from(bytes: &[u8]) -> Self {
         fn hex_digit(b: u8) -> u8 {
             if b <= 9 {
@@ -69,7 +69,7 @@ impl From<Hex> for Vec<u8> {
         }
         let n = value.0.len();
 
-        if n % 2 != 0 {
+        if !n.is_multiple_of(2) {
             unreachable!("hex length is not even");
         }
 
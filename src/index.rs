@@ -0,0 +1,267 @@
+//stat cache of the working directory, stored in .log/index
+//
+//for every file it records the size, mtime and inode it had when it was
+//hashed, and the tree entry it got. a file whose stat data is unchanged
+//reuses that entry instead of being read again
+//
+//layout: header, hash of the settings that shape entries, varint count, then
+//path, size, mtime seconds and nanoseconds, inode and tree entry of every file,
+//followed by a blake3 checksum of everything before it. an index that is
+//missing, corrupt or written with other settings is ignored and rebuilt
+use std::{
+    collections::BTreeMap,
+    fs::{read, Metadata},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    atomic::write_atomic,
+    blob::Blob,
+    config::Config,
+    directory::{DirectoryEntry, FileMode},
+    encoding::{write_blob, write_bytes, write_varint, Reader},
+};
+
+//version 2 entries record the mode and size of files
+const HEADER: &[u8] = b"LOGINDEX\x02";
+//files modified this close to the start of a snapshot could change again
+//within the same timestamp, so they are not cached
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    inode: u64,
+}
+
+impl Stat {
+    fn of(meta: &Metadata) -> Option<Self> {
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stat {
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            inode: inode(meta),
+        })
+    }
+
+    fn mtime(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.mtime_secs, self.mtime_nanos)
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(meta)
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    0
+}
+
+pub struct StatIndex {
+    path: PathBuf,
+    settings: Blob,
+    started: SystemTime,
+    cached: BTreeMap<String, (Stat, DirectoryEntry)>,
+    //files seen by the current snapshot, which make up the next index
+    seen: Mutex<BTreeMap<String, (Stat, DirectoryEntry)>>,
+}
+
+impl StatIndex {
+    //never fails, a missing or unreadable index is just empty
+    pub fn load(path: PathBuf, config: &Config) -> Self {
+        let settings = settings_hash(config);
+        let cached = read(&path)
+            .ok()
+            .and_then(|bytes| parse(&bytes, settings))
+            .unwrap_or_default();
+        StatIndex {
+            path,
+            settings,
+            started: SystemTime::now(),
+            cached,
+            seen: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    //the cached entry of the file at `path`, if it wasn't touched since it was hashed
+    //a change of mode doesn't touch the modification time, so it is compared too
+    pub fn lookup(&self, path: &str, meta: &Metadata) -> Option<DirectoryEntry> {
+        let stat = Stat::of(meta)?;
+        let (cached_stat, entry) = self.cached.get(path)?;
+        if *cached_stat != stat || entry.mode() != FileMode::of(meta) {
+            return None;
+        }
+        self.record(path, meta, entry);
+        Some(entry.clone())
+    }
+
+    //remember the entry of a file hashed while its stat data was `meta`
+    pub fn record(&self, path: &str, meta: &Metadata, entry: &DirectoryEntry) {
+        let Some(stat) = Stat::of(meta) else {
+            return;
+        };
+        if stat.mtime() + RACY_WINDOW > self.started {
+            return;
+        }
+        self.seen
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(path.to_string(), (stat, entry.clone()));
+    }
+
+    //write the files seen by this snapshot, files which are gone drop out
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let seen = self.seen.lock().unwrap_or_else(|err| err.into_inner());
+        let mut out = HEADER.to_vec();
+        write_blob(&mut out, &self.settings);
+        write_varint(&mut out, seen.len() as u64);
+        for (path, (stat, entry)) in seen.iter() {
+            write_bytes(&mut out, path.as_bytes());
+            write_varint(&mut out, stat.size);
+            write_varint(&mut out, stat.mtime_secs);
+            write_varint(&mut out, stat.mtime_nanos as u64);
+            write_varint(&mut out, stat.inode);
+            entry.write(&mut out);
+        }
+        let checksum = Blob::checksum(&out);
+        write_blob(&mut out, &checksum);
+        write_atomic(&self.path, &out)
+    }
+}
+
+//settings which change the entry a file gets
+fn settings_hash(config: &Config) -> Blob {
+    let settings = serde_json::to_vec(&(&config.chunking, &config.large_files)).unwrap_or_default();
+    Blob::checksum(&settings)
+}
+
+fn parse(bytes: &[u8], settings: Blob) -> Option<BTreeMap<String, (Stat, DirectoryEntry)>> {
+    let content = bytes.strip_prefix(HEADER)?;
+    let (content, checksum) = content.split_at(content.len().checked_sub(32)?);
+    if Blob::checksum(&bytes[..bytes.len() - 32]).as_bytes() != checksum {
+        return None;
+    }
+    let mut input = Reader::new(content);
+    if input.blob().ok()? != settings {
+        return None;
+    }
+    let mut cached = BTreeMap::new();
+    for _ in 0..input.varint().ok()? {
+        let path = input.string().ok()?;
+        let stat = Stat {
+            size: input.varint().ok()?,
+            mtime_secs: input.varint().ok()?,
+            mtime_nanos: u32::try_from(input.varint().ok()?).ok()?,
+            inode: input.varint().ok()?,
+        };
+        let entry = DirectoryEntry::read(&mut input).ok()?;
+        cached.insert(path, (stat, entry));
+    }
+    input.is_at_end().then_some(cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LargeFilesConfig;
+    use std::{
+        fs::{metadata, File},
+        path::Path,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-index-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    //a file last modified `age` before now
+    fn file(dir: &Path, name: &str, content: &[u8], age: Duration) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    fn entry(content: &[u8]) -> DirectoryEntry {
+        DirectoryEntry::File {
+            content: Blob::from_bytes([content.len() as u8; 32]),
+            mode: FileMode::Regular,
+            size: Some(content.len() as u64),
+        }
+    }
+
+    //the entry a later snapshot finds for the file
+    fn cached(dir: &Path, path: &Path, config: &Config) -> Option<DirectoryEntry> {
+        let index = StatIndex::load(dir.join("index"), config);
+        index.lookup("f", &metadata(path).unwrap())
+    }
+
+    //a snapshot that hashed the file
+    fn snapshot(dir: &Path, path: &Path, content: &[u8]) {
+        let index = StatIndex::load(dir.join("index"), &Config::default());
+        index.record("f", &metadata(path).unwrap(), &entry(content));
+        index.save().unwrap();
+    }
+
+    #[test]
+    fn files_modified_before_the_window_are_cached() {
+        let dir = temp_dir("old");
+        let path = file(&dir, "f", b"old", Duration::from_secs(60));
+        snapshot(&dir, &path, b"old");
+        assert_eq!(cached(&dir, &path, &Config::default()), Some(entry(b"old")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn racy_files_are_not_cached() {
+        let dir = temp_dir("racy");
+        let path = file(&dir, "f", b"new", Duration::ZERO);
+        snapshot(&dir, &path, b"new");
+        assert_eq!(cached(&dir, &path, &Config::default()), None);
+        //a file from the future is racy as well
+        let path = file(&dir, "f", b"new", Duration::ZERO);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        snapshot(&dir, &path, b"new");
+        assert_eq!(cached(&dir, &path, &Config::default()), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_miss_the_cache() {
+        let dir = temp_dir("changed");
+        let path = file(&dir, "f", b"old", Duration::from_secs(120));
+        snapshot(&dir, &path, b"old");
+        //same size, other mtime
+        let path = file(&dir, "f", b"new", Duration::from_secs(60));
+        assert_eq!(cached(&dir, &path, &Config::default()), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_settings_ignore_the_index() {
+        let dir = temp_dir("settings");
+        let path = file(&dir, "f", b"old", Duration::from_secs(60));
+        snapshot(&dir, &path, b"old");
+        let config = Config {
+            large_files: Some(LargeFilesConfig::default()),
+            ..Config::default()
+        };
+        assert_eq!(cached(&dir, &path, &config), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+//large files kept out of the object store, see DirectoryEntry::Large
+//
+//trees only record the id and size of such files. their content is kept in
+//.log/large, from where it can be pruned, and fetched again from the large store
+//of another repository. a file whose content is missing is checked out as a
+//small placeholder, which snapshots back to the same entry
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::Read,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    atomic::AtomicFile,
+    blob::Blob,
+    commit::Commit,
+    config::LargeFilesConfig,
+    directory::{join_path, work_path, Directory, DirectoryEntry, FileMode},
+    dot_log::{Error, JSON},
+    objects::{BoxedObjects, Objects},
+};
+
+const PLACEHOLDER: &str = "log large file";
+//placeholders are never longer than this, bigger files are not parsed
+pub const PLACEHOLDER_MAX: u64 = 128;
+
+pub struct LargeFiles {
+    config: LargeFilesConfig,
+    objects: BoxedObjects,
+    source: Option<BoxedObjects>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FetchReport {
+    pub fetched: Vec<Blob>,
+    //neither here nor in the source
+    pub unavailable: Vec<Blob>,
+    //placeholders replaced by their content
+    pub materialized: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub removed: Vec<Blob>,
+    pub kept: usize,
+}
+
+impl LargeFiles {
+    pub fn new(
+        config: LargeFilesConfig,
+        objects: BoxedObjects,
+        source: Option<BoxedObjects>,
+    ) -> Self {
+        LargeFiles {
+            config,
+            objects,
+            source,
+        }
+    }
+
+    //whether a file with this name and size is kept out of the object store
+    pub fn matches(&self, name: &str, size: u64) -> bool {
+        self.config
+            .min_size
+            .is_some_and(|min_size| size >= min_size)
+            || self
+                .config
+                .patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, name))
+    }
+
+    pub fn push(&self, file: &mut File) -> Result<Blob, std::io::Error> {
+        self.objects.push_reader(file)
+    }
+
+    //the content from here, else read from the source without copying it
+    //None when neither has it
+    pub fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, std::io::Error> {
+        if let Some(reader) = self.objects.get_reader(id)? {
+            return Ok(Some(reader));
+        }
+        match &self.source {
+            Some(source) => source.get_reader(id),
+            None => Ok(None),
+        }
+    }
+
+    //whether the content is here or can be fetched from the source
+    pub fn knows(&self, id: Blob) -> Result<bool, std::io::Error> {
+        Ok(self.objects.exists(id)?
+            || match &self.source {
+                Some(source) => source.exists(id)?,
+                None => false,
+            })
+    }
+
+    //copy the objects missing here from the source
+    pub fn fetch(
+        &self,
+        ids: &BTreeSet<Blob>,
+        report: &mut FetchReport,
+    ) -> Result<(), std::io::Error> {
+        for id in ids {
+            if self.objects.exists(*id)? {
+                continue;
+            }
+            let reader = match &self.source {
+                Some(source) => source.get_reader(*id)?,
+                None => None,
+            };
+            match reader {
+                Some(mut reader) => {
+                    if self.objects.push_reader(&mut reader)? != *id {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("large file {} fetched with other content", id),
+                        ));
+                    }
+                    report.fetched.push(*id);
+                }
+                None => report.unavailable.push(*id),
+            }
+        }
+        Ok(())
+    }
+
+    //write the content of the files in `files` that are still placeholders
+    //paths are under the work tree `root`
+    pub fn materialize(
+        &self,
+        files: &BTreeMap<String, Blob>,
+        root: &Path,
+        report: &mut FetchReport,
+    ) -> Result<(), std::io::Error> {
+        for (path, id) in files {
+            let Some(file_path) = work_path(root, path) else {
+                continue;
+            };
+            //a symlink put in place of the placeholder is left alone
+            let placeholder = match std::fs::symlink_metadata(&file_path) {
+                Ok(meta) if meta.is_file() && meta.len() <= PLACEHOLDER_MAX => {
+                    read_placeholder(&std::fs::read(&file_path)?)
+                        .is_some_and(|(content, _)| content == *id)
+                        .then_some(meta)
+                }
+                _ => None,
+            };
+            let Some(placeholder) = placeholder else {
+                continue;
+            };
+            if let Some(mut reader) = self.objects.get_reader(*id)? {
+                let mut file = AtomicFile::create(&file_path)?;
+                std::io::copy(&mut reader, &mut file)?;
+                file.commit()?;
+                //the placeholder was checked out with the mode of the file
+                FileMode::of(&placeholder).apply(&file_path)?;
+                report.materialized.push(path.clone());
+            }
+        }
+        Ok(())
+    }
+
+    //delete the stored files not in `keep`, unless no other copy can be fetched and
+    //`referenced` still uses them
+    pub fn prune(
+        &self,
+        keep: &BTreeSet<Blob>,
+        referenced: &BTreeSet<Blob>,
+    ) -> Result<PruneReport, std::io::Error> {
+        let mut report = PruneReport::default();
+        for id in self.objects.ids()? {
+            let fetchable = match &self.source {
+                Some(source) => source.exists(id)?,
+                None => false,
+            };
+            if keep.contains(&id) || (referenced.contains(&id) && !fetchable) {
+                report.kept += 1;
+            } else {
+                report.removed.push(id);
+            }
+        }
+        self.objects
+            .delete(&report.removed.iter().copied().collect())?;
+        Ok(report)
+    }
+}
+
+//`*` stands for any run of characters, everything else matches itself
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let name = match name.strip_prefix(prefix) {
+                Some(name) => name,
+                None => return false,
+            };
+            (0..=name.len())
+                .filter(|at| name.is_char_boundary(*at))
+                .any(|at| matches_pattern(rest, &name[at..]))
+        }
+    }
+}
+
+pub fn placeholder(id: Blob, size: u64) -> String {
+    format!("{} {} {}\n", PLACEHOLDER, id, size)
+}
+
+//id and size recorded in a placeholder
+pub fn read_placeholder(content: &[u8]) -> Option<(Blob, u64)> {
+    let content = std::str::from_utf8(content).ok()?;
+    let rest = content.strip_prefix(PLACEHOLDER)?.strip_suffix('\n')?;
+    let mut fields = rest.split(' ').skip(1);
+    let id = Blob::from_hex(fields.next()?)?;
+    let size = fields.next()?.parse().ok()?;
+    match fields.next() {
+        None => Some((id, size)),
+        Some(_) => None,
+    }
+}
+
+//path and id of every large file in the tree
+pub fn large_entries<O: JSON + ?Sized>(
+    objects: &O,
+    tree: &Directory,
+    out: &mut BTreeMap<String, Blob>,
+) -> Result<(), Error> {
+    collect_large(objects, tree, "", out)
+}
+
+fn collect_large<O: JSON + ?Sized>(
+    objects: &O,
+    tree: &Directory,
+    prefix: &str,
+    out: &mut BTreeMap<String, Blob>,
+) -> Result<(), Error> {
+    for (name, entry) in tree.entries() {
+        match entry {
+            DirectoryEntry::Large { content, .. } => {
+                out.insert(join_path(prefix, name), *content);
+            }
+            DirectoryEntry::Directory(dir) => collect_large(objects, dir, &join_path(prefix, name), out)?,
+            DirectoryEntry::Tree(tree) => {
+                let tree: Directory = objects.read_json(*tree)?;
+                collect_large(objects, &tree, &join_path(prefix, name), out)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+//large files used by any of `commits` or their history
+pub fn referenced<O: JSON + ?Sized>(
+    objects: &O,
+    mut commits: Vec<Blob>,
+) -> Result<BTreeSet<Blob>, Error> {
+    let mut seen = BTreeSet::new();
+    let mut large = BTreeMap::new();
+    let mut ids = BTreeSet::new();
+    while let Some(commit_id) = commits.pop() {
+        if !seen.insert(commit_id) {
+            continue;
+        }
+        let commit: Commit = objects.read_json(commit_id)?;
+        commits.extend(commit.previous);
+        if seen.insert(commit.directory) {
+            let tree: Directory = objects.read_json(commit.directory)?;
+            large_entries(objects, &tree, &mut large)?;
+            ids.extend(std::mem::take(&mut large).into_values());
+        }
+    }
+    Ok(ids)
+}
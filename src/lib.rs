@@ -2,6 +2,9 @@
 
 pub mod hex;
 
+//crash-safe file writes through a temporary file and rename
+pub mod atomic;
+
 //.log directory (similar to .git)
 pub mod dot_log;
 //Binary large object(similar to blob from git)
@@ -12,5 +15,32 @@ pub mod objects;
 //
 pub mod directory;
 
+//repository settings from .log/config
+pub mod config;
+
+//on-disk format version and features from .log/format
+pub mod format;
+
+//canonical binary encoding of trees and commits
+pub mod encoding;
+
+//content-defined chunking of large files
+pub mod chunker;
+
 //commit
 pub mod commit;
+
+//revision expressions naming commits, like HEAD~2 or a short id
+pub mod revision;
+
+//garbage collection of unreachable objects
+pub mod gc;
+
+//integrity check of the repository
+pub mod fsck;
+
+//stat cache of the working directory
+pub mod index;
+
+//large files kept out of the object store
+pub mod large;
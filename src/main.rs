@@ -1,48 +1,186 @@
 use clap::Parser;
 use std::process;
-use std::{env::current_dir, io::stdout};
+use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    env::current_dir,
+    path::PathBuf,
+    io::{stderr, stdout, Write},
+};
 
 #[derive(Parser, Debug)]
 struct Arguments {
     #[clap(subcommand)]
     command: Command,
+    #[clap(long, global = true, help = "Print object cache statistics to stderr when done")]
+    cache_stats: bool,
 }
 use lib::{
+    blob::Blob,
     commit::Commit,
+    chunker::ChunkManifest,
+    config::{ChunkingConfig, Config, EncryptionConfig, HashAlgorithm, LargeFilesConfig, ObjectEncoding, ObjectStore},
     directory::Directory,
     dot_log::{self, JSON},
+    encoding,
+    format::{self, Problem},
+    fsck, gc,
+    large::{self, FetchReport},
+    revision::{self, Expression},
+    objects::{
+        cache::CachedObjects,
+        encrypted::{self, EncryptedObjects},
+        BoxedObjects, ObjectKind, Objects,
+    },
 };
 
 #[derive(Parser, Debug)]
 enum Command {
     #[clap(about = "Initialize a new repo")]
-    Init,
-    #[clap(about = "Highlight differences between current branch and selected one")]
-    Diff { branch: String },
+    Init {
+        #[clap(long, help = "Store large files as content-defined chunks")]
+        chunking: bool,
+        #[clap(long, value_enum, default_value_t = ObjectEncoding::Binary, help = "How trees and commits are stored")]
+        encoding: ObjectEncoding,
+        #[clap(long, value_enum, default_value_t = ObjectStore::Directory, help = "Where objects are kept")]
+        store: ObjectStore,
+        #[clap(long = "alternate", help = "Read objects missing here from the .log folder of another repository")]
+        alternates: Vec<PathBuf>,
+        #[clap(long, help = "Encrypt objects with a key from LOG_ENCRYPTION_KEY or the key file")]
+        encrypt: bool,
+        #[clap(long, requires = "encrypt", help = "File holding the encryption key, created when missing")]
+        key_file: Option<PathBuf>,
+        #[clap(long = "large", help = "Keep files with matching names in the large file store, `*` matches anything")]
+        large_patterns: Vec<String>,
+        #[clap(long, help = "Keep files of at least this many bytes in the large file store")]
+        large_min_size: Option<u64>,
+        #[clap(long, help = "Fetch missing large files from the .log folder of another repository")]
+        large_source: Option<PathBuf>,
+        #[clap(long, value_enum, default_value_t = HashAlgorithm::Blake3, help = "Hash algorithm object ids are computed with")]
+        hash: HashAlgorithm,
+    },
+    #[clap(about = "Highlight differences between the working directory and a revision")]
+    Diff { revision: String },
     #[clap(about = "Provide information about current state(current branch, modified files)")]
     Status,
     #[clap(about = "Branch to checkout")]
-    Checkout { branch: String },
-    #[clap(about = "Merge current branch and selected one")]
-    Merge { branch: String },
+    Checkout {
+        #[clap(help = "Branch, created at the current commit when missing. Other revisions are refused, there is no detached HEAD")]
+        branch: String,
+    },
+    #[clap(about = "Merge a revision into the current branch")]
+    Merge { revision: String },
     #[clap(about = "Commit repository changes with a message")]
     Commit { message: String },
+    #[clap(about = "Move loose objects into a delta-compressed pack")]
+    Repack,
+    #[clap(about = "Delete objects that no branch can reach")]
+    Gc {
+        #[clap(long, help = "Only list the objects that would be deleted")]
+        dry_run: bool,
+        #[clap(
+            long,
+            default_value_t = 336,
+            help = "Keep unreachable objects written within this many hours"
+        )]
+        grace_hours: u64,
+    },
+    #[clap(about = "Verify the integrity of objects, commits and branches")]
+    Fsck,
+    #[clap(about = "Rewrite the single-file object store without unreachable objects")]
+    Compact {
+        #[clap(
+            long,
+            default_value_t = 336,
+            help = "Keep unreachable objects written within this many hours"
+        )]
+        grace_hours: u64,
+    },
+    #[clap(about = "Migrate the repository to the current on-disk format")]
+    Upgrade,
+    #[clap(about = "Fetch the large files of the current commit and replace their placeholders")]
+    FetchLarge,
+    #[clap(about = "Delete stored large files the current commit doesn't use")]
+    PruneLarge,
+    #[clap(about = "List commits of a revision's history or of a range (a..b, a...b)")]
+    Log {
+        #[clap(default_value = "HEAD")]
+        revision: String,
+    },
+    #[clap(about = "Print the content of an object")]
+    CatObject {
+        #[clap(help = "Object id, full or abbreviated, or a revision")]
+        id: String,
+        #[clap(long, help = "Print the kind of the object instead of its content")]
+        kind: bool,
+    },
 }
 
 fn main() {
     let args = Arguments::parse();
+    let cache_stats = args.cache_stats;
+
+    //refuse repositories this version can't read before touching them
+    if !matches!(args.command, Command::Init { .. } | Command::Upgrade) {
+        check_format();
+    }
 
     match args.command {
-        Command::Init => match dot_log::DotLog::init(current_dir().unwrap().join(".log")) {
-            Ok(_) => {}
-            Err(err) => {
-                println!("{:?}", err);
+        Command::Init {
+            chunking,
+            encoding,
+            store,
+            alternates,
+            encrypt,
+            key_file,
+            large_patterns,
+            large_min_size,
+            large_source,
+            hash,
+        } => {
+            let large_source = large_source
+                .map(|path| path.canonicalize().expect("large file source not found"));
+            let large_files = if large_patterns.is_empty() && large_min_size.is_none() && large_source.is_none() {
+                None
+            } else {
+                Some(LargeFilesConfig {
+                    patterns: large_patterns,
+                    min_size: large_min_size,
+                    source: large_source,
+                })
+            };
+            let encryption = encrypt.then(|| {
+                let key = encrypted::init_key(key_file.as_deref()).expect("error at getting the encryption key");
+                EncryptionConfig {
+                    //with LOG_ENCRYPTION_KEY set the key file isn't created, so it may not exist yet
+                    key_file: key_file.map(|path| std::path::absolute(path).expect("invalid key file path")),
+                    key_check: EncryptedObjects::<()>::key_check(&key),
+                }
+            });
+            let config = Config {
+                chunking: chunking.then(ChunkingConfig::default),
+                encoding,
+                store,
+                alternates: alternates
+                    .iter()
+                    .map(|path| path.canonicalize().expect("alternate repository not found"))
+                    .collect(),
+                encryption,
+                large_files,
+                hash,
+                ..Config::default()
+            };
+            match dot_log::DotLog::init(current_dir().unwrap().join(".log"), &config) {
+                Ok(_) => {}
+                Err(err) => {
+                    println!("{:?}", err);
+                }
             }
-        },
-        Command::Diff { branch } => {
+        }
+        Command::Diff { revision } => {
             let current_branch: String;
             let current_directory = current_dir().expect("Error at getting current path");
-            let dot_log = match dot_log::DotLog::is_log_repo(current_directory.join(".log")) {
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
                 Some(repo) => {
                     current_branch = dot_log::DotLog::get_branch(&repo).expect("error at getting current branch!");
                     println!("Current branch: {}", current_branch);
@@ -53,15 +191,19 @@ fn main() {
                     process::exit(0);
                 }
             };
-            let mut objects = dot_log.get_objects().expect("Error at getting objects!");
-            let selected_branch_commit_hash = dot_log
-                .get_branch_commit_hash(&branch)
-                .expect("Error at getting last commit hash from current branch!");
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
+            let selected_branch_commit_hash = resolve_revision(&dot_log, &objects, &revision);
             let ignores = dot_log
                 .ignores()
                 .expect("Error at getting files to be ignored!");
+            let config = dot_log
+                .config()
+                .expect("Error at getting repository config!");
+            let index = dot_log.index(&config);
+            let large = dot_log.large_files(&config).expect("error at opening the large file store");
             let current_branch_tree =
-                Directory::new(current_directory.as_path(), &ignores, &mut objects).expect("error at getting current branch tree");
+                Directory::new_indexed(&dot_log.work_tree(), &ignores, &config, &objects, &index, large.as_ref()).expect("error at getting current branch tree");
+            index.save().expect("error at saving the index");
             let selected_branch_commit_entry: Commit = objects
                 .read_json(selected_branch_commit_hash)
                 .expect("Error at getting commit data");
@@ -70,14 +212,17 @@ fn main() {
                 .expect("error at getting selected branch commit tree");
             serde_json::to_writer_pretty(
                 stdout(),
-                &current_branch_tree.diff(&selected_branch_commit_tree),
+                &current_branch_tree
+                    .diff(&selected_branch_commit_tree, &objects)
+                    .expect("error at comparing trees"),
             )
             .expect("error at displaying json data");
+            print_cache_stats(&objects, cache_stats);
         }
         Command::Status => {
             let current_branch: String;
             let current_directory = current_dir().expect("Error at getting current path");
-            let dot_log = match dot_log::DotLog::is_log_repo(current_directory.join(".log")) {
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
                 Some(repo) => {
                     current_branch = dot_log::DotLog::get_branch(&repo).unwrap();
                     println!("Current branch: {}", current_branch);
@@ -88,25 +233,34 @@ fn main() {
                     process::exit(0);
                 }
             };
-            let mut objects = dot_log.get_objects().expect("Error at getting objects!");
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
             let commit_hash = dot_log
                 .get_branch_commit_hash(&current_branch)
                 .expect("Error at getting last commit hash from current branch!");
             let ignores = dot_log
                 .ignores()
                 .expect("Error at getting files to be ignored!");
+            let config = dot_log
+                .config()
+                .expect("Error at getting repository config!");
+            let index = dot_log.index(&config);
+            let large = dot_log.large_files(&config).expect("error at opening the large file store");
             let directory =
-                Directory::new(current_directory.as_path(), &ignores, &mut objects).expect("");
+                Directory::new_indexed(&dot_log.work_tree(), &ignores, &config, &objects, &index, large.as_ref()).expect("");
+            index.save().expect("error at saving the index");
             let commit: Commit = objects
                 .read_json(commit_hash)
                 .expect("Error at getting commit data");
             let commit_directory: Directory = objects.read_json(commit.directory).expect("e");
-            serde_json::to_writer_pretty(stdout(), &commit_directory.diff(&directory))
-                .expect("error at displaying json data");
+            let diff = commit_directory
+                .diff(&directory, &objects)
+                .expect("error at comparing trees");
+            serde_json::to_writer_pretty(stdout(), &diff).expect("error at displaying json data");
+            print_cache_stats(&objects, cache_stats);
         }
         Command::Checkout { branch } => {
             let current_directory = current_dir().expect("Error at getting current path");
-            let dot_log = match dot_log::DotLog::is_log_repo(current_directory.join(".log")) {
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
                 Some(repo) => repo,
                 None => {
                     println!("Not a log repo!");
@@ -115,15 +269,21 @@ fn main() {
             };
 
             if dot_log.branch_exists(&branch) {
-                let mut objects = dot_log.get_objects().expect("Error at getting objects!");
+                let objects = dot_log.get_objects().expect("Error at getting objects!");
                 let selected_branch_commit_hash = dot_log
                     .get_branch_commit_hash(&branch)
                     .expect("Error at getting last commit hash from current branch!");
                 let ignores = dot_log
                     .ignores()
                     .expect("Error at getting files to be ignored!");
+                let config = dot_log
+                    .config()
+                    .expect("Error at getting repository config!");
+                let index = dot_log.index(&config);
+                let large = dot_log.large_files(&config).expect("error at opening the large file store");
                 let current_branch_tree =
-                    Directory::new(current_directory.as_path(), &ignores, &mut objects).expect("error at getting current branch tree");
+                    Directory::new_indexed(&dot_log.work_tree(), &ignores, &config, &objects, &index, large.as_ref()).expect("error at getting current branch tree");
+                index.save().expect("error at saving the index");
                 let selected_branch_commit_entry: Commit = objects
                     .read_json(selected_branch_commit_hash)
                     .expect("Error at getting commit data");
@@ -133,8 +293,11 @@ fn main() {
                 // serde_json::to_writer_pretty(stdout(), &current_branch_tree.diff(&selected_branch_commit_tree)).expect("msg");
                 current_branch_tree.build_branch_working_dir(
                     &selected_branch_commit_tree,
-                    current_directory.join(".log"),
+                    &dot_log.work_tree(),
+                    &objects,
+                    large.as_ref(),
                 );
+                print_cache_stats(&objects, cache_stats);
                 match dot_log.set_branch(&branch) {
                     Ok(_) => {
                         println!("Switched to branch: {}", branch);
@@ -148,19 +311,44 @@ fn main() {
                     }
                 }
             } else {
+                //names like HEAD or a~1 couldn't be told apart from revisions. those
+                //are resolved to report what they name, but only branches are checked
+                //out, as there is no detached HEAD
+                if !revision::valid_branch_name(&branch) {
+                    let revision = match revision::parse(&branch) {
+                        Ok(revision) => revision,
+                        Err(_) => {
+                            println!("Not a valid branch name: {}", branch);
+                            process::exit(1);
+                        }
+                    };
+                    let objects = dot_log.get_objects().expect("Error at getting objects!");
+                    let commit = revision_or_exit(revision.resolve(&dot_log, &objects));
+                    println!(
+                        "{} is commit {}, not a branch: checkout only switches branches, there is no detached HEAD",
+                        branch, commit
+                    );
+                    process::exit(1);
+                }
                 match dot_log.create_branch(&branch) {
                     Ok(_) => {
                         println!("Created branch: {}", branch);
-                        let mut objects = dot_log.get_objects().expect("Error at getting objects!");
+                        let objects = dot_log.get_objects().expect("Error at getting objects!");
                         let selected_branch_commit_hash = dot_log
                             .get_branch_commit_hash(&branch)
                             .expect("Error at getting last commit hash from current branch!");
                         let ignores = dot_log
                             .ignores()
                             .expect("Error at getting files to be ignored!");
+                        let config = dot_log
+                            .config()
+                            .expect("Error at getting repository config!");
+                        let index = dot_log.index(&config);
+                        let large = dot_log.large_files(&config).expect("error at opening the large file store");
                         let current_branch_tree =
-                            Directory::new(current_directory.as_path(), &ignores, &mut objects)
+                            Directory::new_indexed(&dot_log.work_tree(), &ignores, &config, &objects, &index, large.as_ref())
                                 .expect("error at getting current branch tree");
+                        index.save().expect("error at saving the index");
                         let selected_branch_commit_entry: Commit = objects
                             .read_json(selected_branch_commit_hash)
                             .expect("Error at getting commit data");
@@ -169,8 +357,11 @@ fn main() {
                             .expect("error at getting selected branch commit tree");
                         current_branch_tree.build_branch_working_dir(
                             &selected_branch_commit_tree,
-                            current_directory.join(".log"),
+                            &dot_log.work_tree(),
+                            &objects,
+                            large.as_ref(),
                         );
+                        print_cache_stats(&objects, cache_stats);
                         match dot_log.set_branch(&branch) {
                             Ok(_) => {
                                 println!("Switched to branch: {}", branch);
@@ -192,10 +383,10 @@ fn main() {
                 }
             }
         }
-        Command::Merge { branch } => {
+        Command::Merge { revision } => {
             let current_branch: String;
             let current_directory = current_dir().expect("Error at getting current path");
-            let dot_log = match dot_log::DotLog::is_log_repo(current_directory.join(".log")) {
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
                 Some(repo) => {
                     current_branch = dot_log::DotLog::get_branch(&repo).unwrap();
                     println!("Current branch: {}", current_branch);
@@ -206,15 +397,19 @@ fn main() {
                     process::exit(0);
                 }
             };
-            let mut objects = dot_log.get_objects().expect("Error at getting objects!");
-            let selected_branch_commit_hash = dot_log
-                .get_branch_commit_hash(&branch)
-                .expect("Error at getting last commit hash from current branch!");
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
+            let selected_branch_commit_hash = resolve_revision(&dot_log, &objects, &revision);
             let ignores = dot_log
                 .ignores()
                 .expect("Error at getting files to be ignored!");
+            let config = dot_log
+                .config()
+                .expect("Error at getting repository config!");
+            let index = dot_log.index(&config);
+            let large = dot_log.large_files(&config).expect("error at opening the large file store");
             let current_branch_tree =
-                Directory::new(current_directory.as_path(), &ignores, &mut objects).expect("");
+                Directory::new_indexed(&dot_log.work_tree(), &ignores, &config, &objects, &index, large.as_ref()).expect("");
+            index.save().expect("error at saving the index");
             let selected_branch_commit_entry: Commit = objects
                 .read_json(selected_branch_commit_hash)
                 .expect("Error at getting commit data");
@@ -222,12 +417,13 @@ fn main() {
                 .read_json(selected_branch_commit_entry.directory)
                 .expect("error at getting selected branch commit tree");
             current_branch_tree
-                .merge_branches(&selected_branch_commit_tree, current_directory.join(".log"));
+                .merge_branches(&selected_branch_commit_tree, &dot_log.work_tree(), &objects, large.as_ref());
+            print_cache_stats(&objects, cache_stats);
         }
         Command::Commit { message } => {
             let current_branch: String;
             let current_directory = current_dir().expect("Error at getting current path");
-            let dot_log = match dot_log::DotLog::is_log_repo(current_directory.join(".log")) {
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
                 Some(repo) => {
                     current_branch = dot_log::DotLog::get_branch(&repo).unwrap();
                     println!("Current branch: {}", current_branch);
@@ -238,25 +434,377 @@ fn main() {
                     process::exit(0);
                 }
             };
-            let mut objects = dot_log.get_objects().expect("Error at getting objects!");
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
             let last_commit_hash = dot_log
                 .get_branch_commit_hash(&current_branch)
                 .expect("Error at getting last commit hash from current branch!");
             let ignores = dot_log
                 .ignores()
                 .expect("Error at getting files to be ignored!");
+            let config = dot_log
+                .config()
+                .expect("Error at getting repository config!");
+            let index = dot_log.index(&config);
+            let large = dot_log.large_files(&config).expect("error at opening the large file store");
             let directory =
-                Directory::new(current_directory.as_path(), &ignores, &mut objects).expect("");
-            let new_commit_blob = objects.insert_json(&directory).expect("");
+                Directory::new_indexed(&dot_log.work_tree(), &ignores, &config, &objects, &index, large.as_ref()).expect("");
+            index.save().expect("error at saving the index");
+            let new_commit_blob = objects.insert_encoded(&directory, config.encoding).expect("");
             let commit = Commit {
                 directory: new_commit_blob,
                 message,
-                previous: vec![last_commit_hash].into_iter().collect(),
+                previous: vec![last_commit_hash],
             };
-            let new_commit_hash = objects.insert_json(&commit).expect("");
+            let new_commit_hash = objects.insert_encoded(&commit, config.encoding).expect("");
             dot_log
                 .set_branch_commit_hash(&current_branch, new_commit_hash)
                 .expect("error at setting hash for the branch to point");
+            print_cache_stats(&objects, cache_stats);
+        }
+        Command::Repack => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let objects = match dot_log
+                .get_directory_objects()
+                .expect("Error at getting objects!")
+            {
+                Some(objects) => objects,
+                None => {
+                    println!("Only supported by the directory object store!");
+                    process::exit(1);
+                }
+            };
+            let stats = objects.repack().expect("error at repacking objects");
+            serde_json::to_writer_pretty(stdout(), &stats).expect("error at displaying json data");
+        }
+        Command::Gc {
+            dry_run,
+            grace_hours,
+        } => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let objects = dot_log.get_local_objects().expect("Error at getting objects!");
+            let reader = dot_log.get_objects().expect("Error at getting objects!");
+            let report = gc::gc(
+                &dot_log,
+                &objects,
+                &reader,
+                Duration::from_secs(grace_hours * 60 * 60),
+                dry_run,
+            )
+            .expect("error at collecting garbage");
+            serde_json::to_writer_pretty(stdout(), &report).expect("error at displaying json data");
         }
+        Command::Fsck => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let objects = dot_log.get_local_objects().expect("Error at getting objects!");
+            let reader = dot_log.get_objects().expect("Error at getting objects!");
+            let report =
+                fsck::fsck(&dot_log, &objects, &reader).expect("error at checking repository");
+            serde_json::to_writer_pretty(stdout(), &report).expect("error at displaying json data");
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        }
+        Command::CatObject { id, kind } => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
+            let id = match id.parse::<Expression>() {
+                Ok(expression) => revision_or_exit(expression.resolve(&dot_log, &objects)),
+                Err(problem) => revision_or_exit(Err(dot_log::Error::Revision(problem))),
+            };
+            match objects.get_typed(id).expect("error at reading object") {
+                Some((object_kind, content)) => {
+                    if kind {
+                        match object_kind {
+                            Some(object_kind) => println!("{}", object_kind),
+                            None => println!("unknown"),
+                        }
+                    } else if let Some(
+                        decodable @ (ObjectKind::Tree | ObjectKind::Commit | ObjectKind::Manifest),
+                    ) = object_kind.filter(|_| encoding::is_binary(&content))
+                    {
+                        //binary trees, commits and chunk lists are shown as json,
+                        //every other object is written as stored
+                        let decoded = match decodable {
+                            ObjectKind::Tree => encoding::from_binary::<Directory>(&content)
+                                .map(|tree| serde_json::to_value(tree).expect("")),
+                            ObjectKind::Commit => encoding::from_binary::<Commit>(&content)
+                                .map(|commit| serde_json::to_value(commit).expect("")),
+                            _ => encoding::from_binary::<ChunkManifest>(&content)
+                                .map(|manifest| serde_json::to_value(manifest).expect("")),
+                        };
+                        serde_json::to_writer_pretty(
+                            stdout(),
+                            &decoded.expect("error at decoding object"),
+                        )
+                        .expect("error at displaying json data");
+                    } else {
+                        stdout()
+                            .write_all(&content)
+                            .expect("error at displaying object");
+                    }
+                }
+                None => {
+                    println!("No such object: {}", id);
+                    process::exit(1);
+                }
+            }
+            print_cache_stats(&objects, cache_stats);
+        }
+        Command::Log { revision } => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
+            let log = revision_or_exit(
+                revision::parse(&revision).and_then(|revision| revision.log(&dot_log, &objects)),
+            );
+            serde_json::to_writer_pretty(stdout(), &log).expect("error at displaying json data");
+            print_cache_stats(&objects, cache_stats);
+        }
+        Command::Compact { grace_hours } => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let objects = match dot_log
+                .get_append_objects()
+                .expect("Error at getting objects!")
+            {
+                Some(objects) => objects,
+                None => {
+                    println!("Only supported by the append object store!");
+                    process::exit(1);
+                }
+            };
+            //history is read through the configured store, which may decrypt it
+            let reader = dot_log.get_objects().expect("Error at getting objects!");
+            let (keep, _) =
+                gc::reachable_with_borrowers(&dot_log, &reader).expect("error at finding reachable objects");
+            let stats = objects
+                .compact(&keep, Duration::from_secs(grace_hours * 60 * 60))
+                .expect("error at compacting objects");
+            serde_json::to_writer_pretty(stdout(), &stats).expect("error at displaying json data");
+        }
+        Command::Upgrade => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            match dot_log.upgrade() {
+                Ok(report) => {
+                    serde_json::to_writer_pretty(stdout(), &report).expect("error at displaying json data")
+                }
+                Err(dot_log::Error::Format(problem)) => {
+                    println!("{}", format_problem(&problem));
+                    process::exit(1);
+                }
+                Err(dot_log::Error::Cycle(id)) => {
+                    println!("Commit {} is its own ancestor, run fsck to see the cycles", id);
+                    process::exit(1);
+                }
+                Err(err) => panic!("error at upgrading the repository: {:?}", err),
+            }
+        }
+        Command::FetchLarge => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let config = dot_log
+                .config()
+                .expect("Error at getting repository config!");
+            let large = match dot_log.large_files(&config).expect("error at opening the large file store") {
+                Some(large) => large,
+                None => {
+                    println!("Large files are not kept apart in this repository!");
+                    process::exit(1);
+                }
+            };
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
+            let tree = current_commit_tree(&dot_log, &objects);
+            let mut files = BTreeMap::new();
+            large::large_entries(&objects, &tree, &mut files).expect("error at reading the tree");
+            let mut report = FetchReport::default();
+            large
+                .fetch(&files.values().copied().collect(), &mut report)
+                .expect("error at fetching large files");
+            large
+                .materialize(&files, &dot_log.work_tree(), &mut report)
+                .expect("error at writing large files");
+            serde_json::to_writer_pretty(stdout(), &report).expect("error at displaying json data");
+        }
+        Command::PruneLarge => {
+            let current_directory = current_dir().expect("Error at getting current path");
+            let dot_log = match dot_log::DotLog::discover(&current_directory) {
+                Some(repo) => repo,
+                None => {
+                    println!("Not a log repo!");
+                    process::exit(0);
+                }
+            };
+            let config = dot_log
+                .config()
+                .expect("Error at getting repository config!");
+            let large = match dot_log.large_files(&config).expect("error at opening the large file store") {
+                Some(large) => large,
+                None => {
+                    println!("Large files are not kept apart in this repository!");
+                    process::exit(1);
+                }
+            };
+            let objects = dot_log.get_objects().expect("Error at getting objects!");
+            let mut files = BTreeMap::new();
+            large::large_entries(&objects, &current_commit_tree(&dot_log, &objects), &mut files)
+                .expect("error at reading the tree");
+            let heads = dot_log
+                .branches()
+                .expect("error at listing branches")
+                .iter()
+                .map(|branch| dot_log.get_branch_commit_hash(branch).expect("error at reading branch"))
+                .collect();
+            //files no other copy is known of stay while any branch uses them
+            let referenced = large::referenced(&objects, heads).expect("error at reading history");
+            let report = large
+                .prune(&files.into_values().collect(), &referenced)
+                .expect("error at pruning large files");
+            serde_json::to_writer_pretty(stdout(), &report).expect("error at displaying json data");
+        }
+    }
+}
+
+fn check_format() {
+    let current_directory = current_dir().expect("Error at getting current path");
+    if let Some(dot_log) = dot_log::DotLog::discover(&current_directory) {
+        let format = dot_log.format().expect("error at reading the repository format");
+        if let Err(problem) = format.check() {
+            println!("{}", format_problem(&problem));
+            process::exit(1);
+        }
+    }
+}
+
+fn format_problem(problem: &Problem) -> String {
+    match problem {
+        Problem::TooOld(version) => format!(
+            "The repository has format version {}, run `upgrade` to migrate it to version {}!",
+            version,
+            format::VERSION
+        ),
+        Problem::TooNew(version) => format!(
+            "The repository has format version {}, this program only knows up to {}!",
+            version,
+            format::VERSION
+        ),
+        Problem::UnknownFeatures(features) => format!(
+            "The repository uses features this program doesn't know: {}!",
+            features.join(", ")
+        ),
+    }
+}
+
+//commit named by `revision`, see revision
+fn resolve_revision(
+    dot_log: &dot_log::DotLog,
+    objects: &CachedObjects<BoxedObjects>,
+    revision: &str,
+) -> Blob {
+    revision_or_exit(revision::parse(revision).and_then(|revision| revision.resolve(dot_log, objects)))
+}
+
+//a revision that names nothing here is reported, not a crash
+fn revision_or_exit<A>(result: Result<A, dot_log::Error>) -> A {
+    match result {
+        Ok(value) => value,
+        Err(dot_log::Error::Revision(problem)) => {
+            println!("{}", revision_problem(&problem));
+            process::exit(1);
+        }
+        Err(err) => panic!("error at resolving revision: {:?}", err),
+    }
+}
+
+fn revision_problem(problem: &revision::Problem) -> String {
+    match problem {
+        revision::Problem::Invalid(revision) => format!("Not a revision: {}", revision),
+        revision::Problem::Unknown(name) => format!("No branch or object named {}!", name),
+        revision::Problem::Ambiguous(prefix, ids) => {
+            let mut message = format!("{} is ambiguous, it could be:", prefix);
+            for id in ids {
+                message.push_str(&format!("\n  {}", id));
+            }
+            message
+        }
+        revision::Problem::NoParent(revision) => format!("{} doesn't exist, history is shorter", revision),
+        revision::Problem::NotACommit(revision) => format!("{} is not a commit!", revision),
+        revision::Problem::Range(revision) => format!("{} is a range, a single commit is needed here", revision),
+    }
+}
+
+//tree of the last commit of the current branch
+fn current_commit_tree(dot_log: &dot_log::DotLog, objects: &CachedObjects<BoxedObjects>) -> Directory {
+    let branch = dot_log.get_branch().expect("error at getting current branch!");
+    let commit: Commit = objects
+        .read_json(
+            dot_log
+                .get_branch_commit_hash(&branch)
+                .expect("Error at getting last commit hash from current branch!"),
+        )
+        .expect("Error at getting commit data");
+    objects
+        .read_json(commit.directory)
+        .expect("error at getting current branch commit tree")
+}
+
+//on stderr, so that it doesn't mix with the json output
+fn print_cache_stats(objects: &CachedObjects<BoxedObjects>, enabled: bool) {
+    if enabled {
+        serde_json::to_writer_pretty(stderr(), &objects.stats())
+            .expect("error at displaying json data");
+        eprintln!();
     }
 }
@@ -1,17 +1,246 @@
-use crate::blob::Blob;
+use std::{any::Any, collections::BTreeSet, fmt::Display, io::Read, sync::Arc, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blob::{Blob, Hasher};
 
 pub mod directory;
 
 pub mod memory;
 
-pub trait Objects {
+pub mod pack;
+
+pub mod delta;
+
+pub mod append;
+
+pub mod cache;
+
+pub mod alternates;
+
+pub mod encrypted;
+
+//what a stored object holds, recorded next to its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectKind {
+        //file content or a chunk of it
+        Blob,
+        Tree,
+        Commit,
+        Tag,
+        //list of the chunks of a large file
+        Manifest,
+}
+
+impl ObjectKind {
+        pub fn to_byte(self) -> u8 {
+                match self {
+                        ObjectKind::Blob => 1,
+                        ObjectKind::Tree => 2,
+                        ObjectKind::Commit => 3,
+                        ObjectKind::Tag => 4,
+                        ObjectKind::Manifest => 5,
+                }
+        }
+
+        pub fn from_byte(byte: u8) -> Option<Self> {
+                match byte {
+                        1 => Some(ObjectKind::Blob),
+                        2 => Some(ObjectKind::Tree),
+                        3 => Some(ObjectKind::Commit),
+                        4 => Some(ObjectKind::Tag),
+                        5 => Some(ObjectKind::Manifest),
+                        _ => None,
+                }
+        }
+}
+
+impl Display for ObjectKind {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let name = match self {
+                        ObjectKind::Blob => "blob",
+                        ObjectKind::Tree => "tree",
+                        ObjectKind::Commit => "commit",
+                        ObjectKind::Tag => "tag",
+                        ObjectKind::Manifest => "manifest",
+                };
+                write!(f, "{}", name)
+        }
+}
+
+//object data with the kind it was stored as
+//the kind is None for objects written before kinds were recorded
+pub type TypedContent = (Option<ObjectKind>, Vec<u8>);
+
+//types which are stored as objects of a fixed kind
+pub trait TypedObject {
+        const KIND: ObjectKind;
+}
+
+//stores are shared between the threads taking a snapshot, so pushes take &self
+pub trait Objects: Send + Sync {
         type Error;
         //check if blob already exists in /objects
         fn exists(&self, id:Blob) -> Result<bool, Self::Error>;
 
+        //get blob data and its kind from /objects
+        fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error>;
+
+        //push an object of the given kind into /objects
+        fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error>;
+
+        //the kind the object was stored as, None when it is missing, without reading
+        //it whole where the store can tell it apart
+        fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+                Ok(self.get_typed(id)?.map(|(kind, _)| kind))
+        }
+
         //get blob data from /objects
-        fn get(&self, id:Blob) -> Result<Option<Vec<u8>>, Self::Error>;
+        fn get(&self, id:Blob) -> Result<Option<Vec<u8>>, Self::Error> {
+                Ok(self.get_typed(id)?.map(|(_, v)| v))
+        }
 
         //push blob into /objects
-        fn push(&mut self, object: &[u8]) -> Result<Blob, Self::Error>;
-}
\ No newline at end of file
+        fn push(&self, object: &[u8]) -> Result<Blob, Self::Error> {
+                self.push_typed(ObjectKind::Blob, object)
+        }
+
+        //push blob read from a stream, hashing it incrementally instead of loading it whole
+        fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error>;
+
+        //get blob data from /objects as a stream
+        fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error>;
+
+        //value parsed earlier from the object, only caching stores keep them
+        fn get_parsed(&self, _id: Blob) -> Option<Arc<dyn Any + Send + Sync>> {
+                None
+        }
+
+        //offer a value parsed from the object, `size` is the length of the object
+        fn put_parsed(&self, _id: Blob, _value: Arc<dyn Any + Send + Sync>, _size: usize) {}
+
+        //hasher giving the ids of this store, with the hash algorithm of the
+        //repository, encrypted stores key it
+        fn hasher(&self) -> Hasher;
+
+        //id the content would be stored under as a blob
+        fn id_of(&self, content: &[u8]) -> Blob {
+                self.hasher().update(content).finalize()
+        }
+
+        //id the content would be stored under as an object of `kind`
+        //the kind and length go in front of everything but blobs, so the same bytes
+        //pushed as two kinds are two objects. blobs keep the id of their content,
+        //which chunked and large files are compared by
+        fn id_of_typed(&self, kind: ObjectKind, content: &[u8]) -> Blob {
+                let mut hasher = self.hasher();
+                if kind != ObjectKind::Blob {
+                        hasher.update(format!("{} {}\0", kind, content.len()).as_bytes());
+                }
+                hasher.update(content).finalize()
+        }
+
+        //whether `content` of `kind` belongs under `id`, objects of any kind written
+        //before the kind was hashed have the id of their content
+        fn is_id_of(&self, id: Blob, kind: Option<ObjectKind>, content: &[u8]) -> bool {
+                self.id_of(content) == id || kind.is_some_and(|kind| self.id_of_typed(kind, content) == id)
+        }
+
+        //store bytes prepared by a wrapping store under an id it computed
+        fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error>;
+
+        //push_stored for bytes read from a stream, whose id the wrapping store only
+        //knows at the end: `id` is called once `stored` is read to the end
+        fn push_stored_reader(&self, kind: ObjectKind, stored: &mut dyn Read, id: &dyn Fn() -> Blob) -> Result<Blob, Self::Error>;
+
+        //ids of every object in the store
+        fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error>;
+
+        //length of the object content, None when it is missing
+        fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error>;
+
+        //remove the objects, returns how many were there
+        //batched, since stores keeping objects together rewrite them to delete
+        fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error>;
+
+        //when the object was written, None when the store doesn't know
+        fn modified(&self, _id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+                Ok(None)
+        }
+}
+
+//store picked at runtime from the repository config
+pub type BoxedObjects = Box<dyn Objects<Error = std::io::Error>>;
+
+impl<O: Objects + ?Sized> Objects for Box<O> {
+        type Error = O::Error;
+
+        fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
+                (**self).exists(id)
+        }
+
+        fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+                (**self).get_typed(id)
+        }
+
+        fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+                (**self).push_typed(kind, object)
+        }
+
+        fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+                (**self).kind(id)
+        }
+
+        fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+                (**self).push_reader(object)
+        }
+
+        fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+                (**self).get_reader(id)
+        }
+
+        fn get_parsed(&self, id: Blob) -> Option<Arc<dyn Any + Send + Sync>> {
+                (**self).get_parsed(id)
+        }
+
+        fn put_parsed(&self, id: Blob, value: Arc<dyn Any + Send + Sync>, size: usize) {
+                (**self).put_parsed(id, value, size)
+        }
+
+        fn hasher(&self) -> Hasher {
+                (**self).hasher()
+        }
+
+        fn id_of(&self, content: &[u8]) -> Blob {
+                (**self).id_of(content)
+        }
+
+        fn id_of_typed(&self, kind: ObjectKind, content: &[u8]) -> Blob {
+                (**self).id_of_typed(kind, content)
+        }
+
+        fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+                (**self).push_stored(id, kind, stored)
+        }
+
+        fn push_stored_reader(&self, kind: ObjectKind, stored: &mut dyn Read, id: &dyn Fn() -> Blob) -> Result<Blob, Self::Error> {
+                (**self).push_stored_reader(kind, stored, id)
+        }
+
+        fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+                (**self).ids()
+        }
+
+        fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+                (**self).size(id)
+        }
+
+        fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+                (**self).delete(ids)
+        }
+
+        fn modified(&self, id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+                (**self).modified(id)
+        }
+}
@@ -0,0 +1,164 @@
+//local store in front of read-only stores of other repositories
+//
+//lookups fall through to the alternates in order, pushes only write to the
+//local store, and objects an alternate already has are not copied
+use std::{
+    collections::BTreeSet,
+    io::{Cursor, Read},
+    time::SystemTime,
+};
+
+use super::{ObjectKind, Objects, TypedContent};
+use crate::blob::{Blob, Hasher};
+
+const SPOOL_LIMIT: u64 = 8 * 1024 * 1024;
+
+pub struct AlternateObjects<S> {
+    local: S,
+    alternates: Vec<S>,
+}
+
+impl<S> AlternateObjects<S> {
+    pub fn new(local: S, alternates: Vec<S>) -> Self {
+        AlternateObjects { local, alternates }
+    }
+}
+
+impl<S: Objects> Objects for AlternateObjects<S>
+where
+    S::Error: From<std::io::Error>,
+{
+    type Error = S::Error;
+
+    fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
+        if self.local.exists(id)? {
+            return Ok(true);
+        }
+        for alternate in &self.alternates {
+            if alternate.exists(id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+        if let Some(content) = self.local.get_typed(id)? {
+            return Ok(Some(content));
+        }
+        for alternate in &self.alternates {
+            if let Some(content) = alternate.get_typed(id)? {
+                return Ok(Some(content));
+            }
+        }
+        Ok(None)
+    }
+
+    fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+        if let Some(kind) = self.local.kind(id)? {
+            return Ok(Some(kind));
+        }
+        for alternate in &self.alternates {
+            if let Some(kind) = alternate.kind(id)? {
+                return Ok(Some(kind));
+            }
+        }
+        Ok(None)
+    }
+
+    fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+        let blob = self.local.id_of_typed(kind, object);
+        for alternate in &self.alternates {
+            if alternate.exists(blob)? {
+                return Ok(blob);
+            }
+        }
+        self.local.push_typed(kind, object)
+    }
+
+    fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+        for alternate in &self.alternates {
+            if alternate.exists(id)? {
+                return Ok(());
+            }
+        }
+        self.local.push_stored(id, kind, stored)
+    }
+
+    //the id is only known at the end, so the object is always stored locally
+    fn push_stored_reader(
+        &self,
+        kind: ObjectKind,
+        stored: &mut dyn Read,
+        id: &dyn Fn() -> Blob,
+    ) -> Result<Blob, Self::Error> {
+        self.local.push_stored_reader(kind, stored, id)
+    }
+
+    //the id is only known once the stream is read, so objects up to SPOOL_LIMIT are
+    //read first to check the alternates, bigger ones are always stored locally
+    fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+        let mut spool = Vec::new();
+        if let Err(err) = object.take(SPOOL_LIMIT + 1).read_to_end(&mut spool) {
+            return Err(err.into());
+        }
+        if spool.len() as u64 <= SPOOL_LIMIT {
+            return self.push_typed(ObjectKind::Blob, &spool);
+        }
+        self.local.push_reader(&mut Cursor::new(spool).chain(object))
+    }
+
+    fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+        if let Some(reader) = self.local.get_reader(id)? {
+            return Ok(Some(reader));
+        }
+        for alternate in &self.alternates {
+            if let Some(reader) = alternate.get_reader(id)? {
+                return Ok(Some(reader));
+            }
+        }
+        Ok(None)
+    }
+
+    //ids are the ones of the local store, alternates only match when they use the same key
+    fn hasher(&self) -> Hasher {
+        self.local.hasher()
+    }
+
+    fn id_of(&self, content: &[u8]) -> Blob {
+        self.local.id_of(content)
+    }
+
+    fn id_of_typed(&self, kind: ObjectKind, content: &[u8]) -> Blob {
+        self.local.id_of_typed(kind, content)
+    }
+
+    fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+        let mut ids = self.local.ids()?;
+        for alternate in &self.alternates {
+            ids.extend(alternate.ids()?);
+        }
+        Ok(ids)
+    }
+
+    fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+        if let Some(size) = self.local.size(id)? {
+            return Ok(Some(size));
+        }
+        for alternate in &self.alternates {
+            if let Some(size) = alternate.size(id)? {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    //alternates are read-only, only local objects are deleted
+    fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+        self.local.delete(ids)
+    }
+
+    fn modified(&self, id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+        self.local.modified(id)
+    }
+}
@@ -0,0 +1,828 @@
+//single-file object store: objects are appended to objects.data, and
+//objects.idx lists where each of them starts
+//
+//objects.data: header, data id, then records: id, u64 write time in seconds since
+//the unix epoch, u64 length, compressed payload
+//objects.idx: header, data id, then (id, u64 offset) in the order of the records
+//
+//a record is synced before its index entry is written. on open, records past
+//the indexed ones are recovered by scanning, a torn record at the end is cut
+//off, and an index of another data file (an interrupted compact) is rebuilt
+//
+//appends and compacts of all processes take a lock on objects.lock, and first
+//catch up with the records other processes wrote since
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{remove_file, File},
+    io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use super::{
+    directory::{
+        compressor, decompress, decompressor, inflate, typed_decompressor, STREAM_BUFFER,
+    },
+    ObjectKind, Objects, TypedContent,
+};
+use crate::{
+    atomic::{write_atomic, AtomicFile},
+    blob::{Blob, Hasher},
+    config::HashAlgorithm,
+};
+
+const DATA_HEADER: &[u8] = b"LOGDATA\x01";
+const INDEX_HEADER: &[u8] = b"LOGAIDX\x01";
+//random, shared by a data file and its index
+const DATA_ID_LEN: usize = 16;
+const DATA_START: u64 = (DATA_HEADER.len() + DATA_ID_LEN) as u64;
+//id, write time and length in front of every payload
+const RECORD_HEADER: u64 = 48;
+const INDEX_ENTRY: usize = 40;
+
+//distinguishes spool files of concurrent appends in the same process
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct AppendOnlyObjects {
+    data_path: PathBuf,
+    index_path: PathBuf,
+    lock_path: PathBuf,
+    hash: HashAlgorithm,
+    //records hold objects sealed by a wrapping store, so their ids can't be recomputed
+    sealed: bool,
+    //locked while a record is written or the data file is compacted
+    records: Mutex<Records>,
+}
+
+#[derive(Debug)]
+struct Records {
+    data_id: [u8; DATA_ID_LEN],
+    offsets: BTreeMap<Blob, u64>,
+    //end of the last complete record
+    end: u64,
+}
+
+//compressed content of a record that is about to be appended
+enum Payload {
+    Memory(Vec<u8>),
+    //a file beside the data file, removed when dropped
+    Spooled {
+        file: BufWriter<File>,
+        path: PathBuf,
+    },
+}
+
+impl Payload {
+    fn len(&mut self) -> Result<u64, std::io::Error> {
+        match self {
+            Payload::Memory(payload) => Ok(payload.len() as u64),
+            Payload::Spooled { file, .. } => file.stream_position(),
+        }
+    }
+
+    fn copy_to(&mut self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        match self {
+            Payload::Memory(payload) => out.write_all(payload),
+            Payload::Spooled { file, .. } => {
+                file.flush()?;
+                let file = file.get_mut();
+                file.seek(SeekFrom::Start(0))?;
+                std::io::copy(file, out).map(|_| ())
+            }
+        }
+    }
+}
+
+impl Write for Payload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Payload::Memory(payload) => payload.write(buf),
+            Payload::Spooled { file, .. } => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Payload::Memory(_) => Ok(()),
+            Payload::Spooled { file, .. } => file.flush(),
+        }
+    }
+}
+
+impl Drop for Payload {
+    fn drop(&mut self) {
+        if let Payload::Spooled { path, .. } = self {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+//what comes before the payload of a record
+struct RecordHeader {
+    id: Blob,
+    written: u64,
+    len: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CompactStats {
+    pub kept: usize,
+    //unreachable objects kept because they were written within the grace period
+    pub recent: usize,
+    pub removed: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl AppendOnlyObjects {
+    pub fn open(root: &Path, hash: HashAlgorithm, sealed: bool) -> Result<Self, std::io::Error> {
+        let store = AppendOnlyObjects {
+            data_path: root.join("objects.data"),
+            index_path: root.join("objects.idx"),
+            lock_path: root.join("objects.lock"),
+            hash,
+            sealed,
+            //no data file has an all zero id, so the first refresh reads everything
+            records: Mutex::new(Records {
+                data_id: [0u8; DATA_ID_LEN],
+                offsets: BTreeMap::new(),
+                end: DATA_START,
+            }),
+        };
+        {
+            let mut records = store.records();
+            let _lock = store.lock()?;
+            if !store.data_path.exists() {
+                let data_id = new_data_id();
+                write_atomic(&store.index_path, &[INDEX_HEADER, &data_id].concat())?;
+                write_atomic(&store.data_path, &[DATA_HEADER, &data_id].concat())?;
+            }
+            store.refresh(&mut records)?;
+        }
+        Ok(store)
+    }
+
+    fn records(&self) -> MutexGuard<'_, Records> {
+        //records are only updated once an append is complete, so they stay consistent
+        self.records.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    //held while appending or compacting, released when the file is dropped
+    fn lock(&self) -> Result<File, std::io::Error> {
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_path)?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    //reload the records if another process appended to or compacted the data file
+    //the lock must be held
+    fn refresh(&self, records: &mut Records) -> Result<(), std::io::Error> {
+        let mut data = File::open(&self.data_path)?;
+        let mut header = [0u8; DATA_START as usize];
+        data.read_exact(&mut header)
+            .map_err(|_| invalid("truncated object data file"))?;
+        if !header.starts_with(DATA_HEADER) {
+            return Err(invalid("bad object data header"));
+        }
+        let mut data_id = [0u8; DATA_ID_LEN];
+        data_id.copy_from_slice(&header[DATA_HEADER.len()..]);
+        let data_len = data.metadata()?.len();
+        if data_id == records.data_id && data_len == records.end {
+            return Ok(());
+        }
+
+        //the data id is only set once the records are complete again
+        *records = Records {
+            data_id: [0u8; DATA_ID_LEN],
+            offsets: BTreeMap::new(),
+            end: DATA_START,
+        };
+        let indexed = self.load_index(&mut data, data_len, &data_id, records)?;
+        if !indexed {
+            records.offsets.clear();
+            records.end = DATA_START;
+        }
+        let recovered = self.recover(&mut data, data_len, records)?;
+        records.data_id = data_id;
+        if !indexed || recovered {
+            self.write_index(records)?;
+        }
+        Ok(())
+    }
+
+    //read the index, false when it has to be rebuilt from the data file
+    fn load_index(
+        &self,
+        data: &mut File,
+        data_len: u64,
+        data_id: &[u8; DATA_ID_LEN],
+        records: &mut Records,
+    ) -> Result<bool, std::io::Error> {
+        let mut v = Vec::new();
+        match File::open(&self.index_path) {
+            Ok(mut file) => file.read_to_end(&mut v)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let entries = match v.strip_prefix(INDEX_HEADER) {
+            Some(rest) if rest.starts_with(data_id) => &rest[DATA_ID_LEN..],
+            _ => return Ok(false),
+        };
+        if entries.len() % INDEX_ENTRY != 0 {
+            return Ok(false);
+        }
+
+        let mut last = None;
+        for entry in entries.chunks(INDEX_ENTRY) {
+            let mut id = [0u8; 32];
+            id.copy_from_slice(&entry[..32]);
+            let mut offset = [0u8; 8];
+            offset.copy_from_slice(&entry[32..]);
+            let offset = u64::from_le_bytes(offset);
+            if offset < DATA_START || offset >= data_len {
+                return Ok(false);
+            }
+            records
+                .offsets
+                .entry(Blob::from_bytes(id))
+                .or_insert(offset);
+            last = last.max(Some(offset));
+        }
+        if let Some(last) = last {
+            let header = read_record_header(data, last)?;
+            records.end = last + RECORD_HEADER + header.len;
+            if records.end > data_len {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    //index the complete records after `end`, and cut off a torn one
+    //true if anything changed
+    fn recover(
+        &self,
+        data: &mut File,
+        data_len: u64,
+        records: &mut Records,
+    ) -> Result<bool, std::io::Error> {
+        let mut recovered = false;
+        while records.end < data_len {
+            match self.read_valid_record(data, records.end, data_len)? {
+                Some(header) => {
+                    records.offsets.entry(header.id).or_insert(records.end);
+                    records.end += RECORD_HEADER + header.len;
+                    recovered = true;
+                }
+                None => {
+                    let file = File::options().write(true).open(&self.data_path)?;
+                    file.set_len(records.end)?;
+                    file.sync_all()?;
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(recovered)
+    }
+
+    //the record at `offset` if it is complete, its payload decompresses cleanly
+    //and, unless it is sealed, its content has its id
+    fn read_valid_record(
+        &self,
+        data: &mut File,
+        offset: u64,
+        data_len: u64,
+    ) -> Result<Option<RecordHeader>, std::io::Error> {
+        if offset + RECORD_HEADER > data_len {
+            return Ok(None);
+        }
+        let header = read_record_header(data, offset)?;
+        if header.len > data_len - offset - RECORD_HEADER {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; header.len as usize];
+        data.read_exact(&mut payload)?;
+        match inflate(&payload) {
+            Some((kind, content)) if self.sealed || self.is_id_of(header.id, kind, &content) => {
+                Ok(Some(header))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn write_index(&self, records: &Records) -> Result<(), std::io::Error> {
+        let mut by_offset: Vec<(u64, Blob)> = records
+            .offsets
+            .iter()
+            .map(|(id, offset)| (*offset, *id))
+            .collect();
+        by_offset.sort();
+        let mut index = [INDEX_HEADER, &records.data_id].concat();
+        for (offset, id) in by_offset {
+            index.extend_from_slice(id.as_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+        }
+        write_atomic(&self.index_path, &index)
+    }
+
+    //compress and hash the content into `payload`, then append it as a record
+    //`known` gives the id when the caller has it, once the content is read, and
+    //the content is not hashed then. only blobs have the id of their content,
+    //other kinds always come with one
+    //the store is only locked while the finished record is written
+    fn append(
+        &self,
+        kind: ObjectKind,
+        object: &mut dyn Read,
+        known: Option<&dyn Fn() -> Blob>,
+        payload: Payload,
+    ) -> Result<Blob, std::io::Error> {
+        let mut encoder = compressor(Some(kind), payload)?;
+        let mut hasher = self.hasher();
+        let mut buffer = vec![0u8; STREAM_BUFFER];
+        loop {
+            let n = match object.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if known.is_none() {
+                hasher.update(&buffer[..n]);
+            }
+            encoder.write_all(&buffer[..n])?;
+        }
+        let mut payload = encoder.finish()?;
+        let blob = match known {
+            Some(known) => known(),
+            None => hasher.finalize(),
+        };
+
+        let mut records = self.records();
+        let _lock = self.lock()?;
+        self.refresh(&mut records)?;
+        if records.offsets.contains_key(&blob) {
+            return Ok(blob);
+        }
+        let start = records.end;
+        let mut data = File::options().write(true).open(&self.data_path)?;
+        data.seek(SeekFrom::Start(start))?;
+        let mut out = BufWriter::new(&mut data);
+        let written = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        out.write_all(blob.as_bytes())?;
+        out.write_all(&written.to_le_bytes())?;
+        out.write_all(&payload.len()?.to_le_bytes())?;
+        payload.copy_to(&mut out)?;
+        out.flush()?;
+        drop(out);
+        let end = data.stream_position()?;
+        data.sync_data()?;
+
+        //the record is on disk, so the index may point at it
+        let mut index = File::options().append(true).open(&self.index_path)?;
+        index.write_all(blob.as_bytes())?;
+        index.write_all(&start.to_le_bytes())?;
+        records.offsets.insert(blob, start);
+        records.end = end;
+        Ok(blob)
+    }
+
+    //payload of a streamed object, kept on disk until it is appended
+    fn spool(&self) -> Result<Payload, std::io::Error> {
+        let path = self.data_path.with_file_name(format!(
+            ".objects.data.spool-{}-{}",
+            std::process::id(),
+            SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Payload::Spooled {
+            file: BufWriter::new(file),
+            path,
+        })
+    }
+
+    //rewrite the data file with only the objects in `keep` and those written
+    //less than `grace` ago, like gc the grace period protects objects of a
+    //commit that is still being written
+    pub fn compact(
+        &self,
+        keep: &BTreeSet<Blob>,
+        grace: Duration,
+    ) -> Result<CompactStats, std::io::Error> {
+        let mut current = self.records();
+        let _lock = self.lock()?;
+        self.refresh(&mut current)?;
+        let now = SystemTime::now();
+        let mut data = File::open(&self.data_path)?;
+        let mut recent = BTreeSet::new();
+        for (id, offset) in &current.offsets {
+            if keep.contains(id) {
+                continue;
+            }
+            let written = written_at(read_record_header(&mut data, *offset)?.written);
+            if now.duration_since(written).unwrap_or_default() < grace {
+                recent.insert(*id);
+            }
+        }
+        let mut stats = self.rewrite(&mut current, &keep.union(&recent).copied().collect())?;
+        stats.recent = recent.len();
+        Ok(stats)
+    }
+
+    //the lock must be held
+    fn rewrite(
+        &self,
+        current: &mut Records,
+        keep: &BTreeSet<Blob>,
+    ) -> Result<CompactStats, std::io::Error> {
+        let mut records: Vec<(u64, Blob)> = current
+            .offsets
+            .iter()
+            .filter(|(id, _)| keep.contains(id))
+            .map(|(id, offset)| (*offset, *id))
+            .collect();
+        records.sort();
+
+        let data_id = new_data_id();
+        let mut old = File::open(&self.data_path)?;
+        let mut out = BufWriter::new(AtomicFile::create(&self.data_path)?);
+        out.write_all(DATA_HEADER)?;
+        out.write_all(&data_id)?;
+        let mut offsets = BTreeMap::new();
+        let mut end = DATA_START;
+        for (offset, id) in records {
+            //records keep the time they were first written
+            let header = read_record_header(&mut old, offset)?;
+            out.write_all(id.as_bytes())?;
+            out.write_all(&header.written.to_le_bytes())?;
+            out.write_all(&header.len.to_le_bytes())?;
+            let copied = std::io::copy(&mut Read::by_ref(&mut old).take(header.len), &mut out)?;
+            if copied != header.len {
+                return Err(invalid("truncated object record"));
+            }
+            offsets.insert(id, end);
+            end += RECORD_HEADER + header.len;
+        }
+        //the new data file has a new data id, so a crash before the new index
+        //is written only leads to a rebuild of the index on the next open
+        out.into_inner().map_err(|err| err.into_error())?.commit()?;
+
+        let stats = CompactStats {
+            kept: offsets.len(),
+            recent: 0,
+            removed: current.offsets.len() - offsets.len(),
+            size_before: current.end,
+            size_after: end,
+        };
+        *current = Records {
+            data_id,
+            offsets,
+            end,
+        };
+        self.write_index(current)?;
+        Ok(stats)
+    }
+}
+
+//leaves `data` at the start of the payload
+fn read_record_header(data: &mut File, offset: u64) -> Result<RecordHeader, std::io::Error> {
+    data.seek(SeekFrom::Start(offset))?;
+    let mut header = [0u8; RECORD_HEADER as usize];
+    data.read_exact(&mut header)?;
+    let mut id = [0u8; 32];
+    id.copy_from_slice(&header[..32]);
+    let mut written = [0u8; 8];
+    written.copy_from_slice(&header[32..40]);
+    let mut len = [0u8; 8];
+    len.copy_from_slice(&header[40..]);
+    Ok(RecordHeader {
+        id: Blob::from_bytes(id),
+        written: u64::from_le_bytes(written),
+        len: u64::from_le_bytes(len),
+    })
+}
+
+//the header of the indexed record of `id`, whose payload has to be within the data file
+fn read_stored_record_header(
+    data: &mut File,
+    id: Blob,
+    offset: u64,
+) -> Result<RecordHeader, std::io::Error> {
+    let data_len = data.metadata()?.len();
+    let header = read_record_header(data, offset)?;
+    if header.id != id {
+        return Err(invalid("object index points at another object"));
+    }
+    if header.len > data_len.saturating_sub(offset + RECORD_HEADER) {
+        return Err(invalid("object record is longer than the data file"));
+    }
+    Ok(header)
+}
+
+//a time past what SystemTime holds is taken as now, like other times in the future
+fn written_at(seconds: u64) -> SystemTime {
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(seconds))
+        .unwrap_or_else(SystemTime::now)
+}
+
+fn new_data_id() -> [u8; DATA_ID_LEN] {
+    let seed = format!("{:?} {}", SystemTime::now(), std::process::id());
+    let mut id = [0u8; DATA_ID_LEN];
+    id.copy_from_slice(&blake3::hash(seed.as_bytes()).as_bytes()[..DATA_ID_LEN]);
+    id
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Objects for AppendOnlyObjects {
+    type Error = std::io::Error;
+
+    fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
+        Ok(self.records().offsets.contains_key(&id))
+    }
+
+    fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+        let offset = match self.records().offsets.get(&id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut data = File::open(&self.data_path)?;
+        let header = read_stored_record_header(&mut data, id, offset)?;
+        let mut payload = vec![0u8; header.len as usize];
+        data.read_exact(&mut payload)?;
+        decompress(payload).map(Some)
+    }
+
+    fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+        let blob = self.id_of_typed(kind, object);
+        if self.records().offsets.contains_key(&blob) {
+            return Ok(blob);
+        }
+        self.append(
+            kind,
+            &mut &object[..],
+            Some(&|| blob),
+            Payload::Memory(Vec::new()),
+        )
+    }
+
+    fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+        if !self.records().offsets.contains_key(&id) {
+            self.append(
+                kind,
+                &mut &stored[..],
+                Some(&|| id),
+                Payload::Memory(Vec::new()),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn push_stored_reader(
+        &self,
+        kind: ObjectKind,
+        stored: &mut dyn Read,
+        id: &dyn Fn() -> Blob,
+    ) -> Result<Blob, Self::Error> {
+        self.append(kind, stored, Some(id), self.spool()?)
+    }
+
+    fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+        self.append(ObjectKind::Blob, object, None, self.spool()?)
+    }
+
+    fn hasher(&self) -> Hasher {
+        Hasher::new(self.hash)
+    }
+
+    fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+        let offset = match self.records().offsets.get(&id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut data = File::open(&self.data_path)?;
+        let header = read_stored_record_header(&mut data, id, offset)?;
+        decompressor(data, offset + RECORD_HEADER, header.len).map(Some)
+    }
+
+    fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+        let offset = match self.records().offsets.get(&id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut data = File::open(&self.data_path)?;
+        let header = read_stored_record_header(&mut data, id, offset)?;
+        let (kind, _) = typed_decompressor(data, offset + RECORD_HEADER, header.len)?;
+        Ok(Some(kind))
+    }
+
+    fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+        Ok(self.records().offsets.keys().copied().collect())
+    }
+
+    fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+        match self.get_reader(id)? {
+            Some(mut content) => Ok(Some(std::io::copy(&mut content, &mut std::io::sink())?)),
+            None => Ok(None),
+        }
+    }
+
+    //rewrites the data file, objects other processes append meanwhile are kept
+    fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+        let mut records = self.records();
+        let _lock = self.lock()?;
+        self.refresh(&mut records)?;
+        if !ids.iter().any(|id| records.offsets.contains_key(id)) {
+            return Ok(0);
+        }
+        let keep = records
+            .offsets
+            .keys()
+            .filter(|id| !ids.contains(id))
+            .copied()
+            .collect();
+        Ok(self.rewrite(&mut records, &keep)?.removed)
+    }
+
+    fn modified(&self, id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+        let offset = match self.records().offsets.get(&id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut data = File::open(&self.data_path)?;
+        let header = read_stored_record_header(&mut data, id, offset)?;
+        Ok(Some(written_at(header.written)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-append-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open(dir: &Path) -> AppendOnlyObjects {
+        AppendOnlyObjects::open(dir, HashAlgorithm::Blake3, false).unwrap()
+    }
+
+    fn data_len(dir: &Path) -> u64 {
+        std::fs::metadata(dir.join("objects.data")).unwrap().len()
+    }
+
+    //index with only the header, as if the process died before writing entries
+    fn clear_index(dir: &Path) {
+        let index = std::fs::read(dir.join("objects.idx")).unwrap();
+        std::fs::write(
+            dir.join("objects.idx"),
+            &index[..INDEX_HEADER.len() + DATA_ID_LEN],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn unindexed_records_are_recovered() {
+        let dir = temp_dir("recover");
+        let store = open(&dir);
+        let first = store.push(b"first").unwrap();
+        let second = store.push(b"second").unwrap();
+        drop(store);
+        clear_index(&dir);
+
+        let store = open(&dir);
+        assert_eq!(store.get(first).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(store.get(second).unwrap(), Some(b"second".to_vec()));
+        //the rebuilt index is written back
+        assert_eq!(
+            std::fs::metadata(dir.join("objects.idx")).unwrap().len(),
+            (INDEX_HEADER.len() + DATA_ID_LEN + 2 * INDEX_ENTRY) as u64
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_records_are_cut_off() {
+        let dir = temp_dir("torn");
+        let store = open(&dir);
+        let first = store.push(b"first").unwrap();
+        let end = data_len(&dir);
+        let second = store.push(b"second").unwrap();
+        drop(store);
+        clear_index(&dir);
+        let data = File::options()
+            .write(true)
+            .open(dir.join("objects.data"))
+            .unwrap();
+        data.set_len(data_len(&dir) - 3).unwrap();
+        drop(data);
+
+        let store = open(&dir);
+        assert!(store.exists(first).unwrap());
+        assert!(!store.exists(second).unwrap());
+        assert_eq!(data_len(&dir), end);
+        //the next record goes where the torn one was
+        store.push(b"second").unwrap();
+        assert_eq!(store.get(second).unwrap(), Some(b"second".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_that_do_not_hash_to_their_id_are_cut_off() {
+        let dir = temp_dir("wrong-id");
+        let store = open(&dir);
+        let first = store.push(b"first").unwrap();
+        let end = data_len(&dir);
+        drop(store);
+        let mut payload = compressor(Some(ObjectKind::Blob), Vec::new()).unwrap();
+        payload.write_all(b"other").unwrap();
+        let payload = payload.finish().unwrap();
+        let mut data = File::options()
+            .append(true)
+            .open(dir.join("objects.data"))
+            .unwrap();
+        data.write_all(Blob::from_bytes([7; 32]).as_bytes())
+            .unwrap();
+        data.write_all(&0u64.to_le_bytes()).unwrap();
+        data.write_all(&(payload.len() as u64).to_le_bytes())
+            .unwrap();
+        data.write_all(&payload).unwrap();
+        drop(data);
+
+        let store = open(&dir);
+        assert!(store.exists(first).unwrap());
+        assert!(!store.exists(Blob::from_bytes([7; 32])).unwrap());
+        assert_eq!(data_len(&dir), end);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_of_another_data_file_is_rebuilt() {
+        let dir = temp_dir("other-index");
+        let store = open(&dir);
+        let id = store.push(b"content").unwrap();
+        drop(store);
+        //an index pointing at nothing, left by an interrupted compact
+        let mut index = INDEX_HEADER.to_vec();
+        index.extend_from_slice(&[1u8; DATA_ID_LEN]);
+        index.extend_from_slice(Blob::from_bytes([7; 32]).as_bytes());
+        index.extend_from_slice(&DATA_START.to_le_bytes());
+        std::fs::write(dir.join("objects.idx"), index).unwrap();
+
+        let store = open(&dir);
+        assert_eq!(store.ids().unwrap(), BTreeSet::from([id]));
+        assert_eq!(store.get(id).unwrap(), Some(b"content".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_recent_objects_and_write_times() {
+        let dir = temp_dir("compact");
+        let store = open(&dir);
+        let kept = store.push(b"kept").unwrap();
+        let dropped = store.push(b"dropped").unwrap();
+        let written = store.modified(kept).unwrap().unwrap();
+        let age = SystemTime::now().duration_since(written).unwrap();
+        assert!(age < Duration::from_secs(60));
+
+        let stats = store
+            .compact(&BTreeSet::from([kept]), Duration::from_secs(60 * 60))
+            .unwrap();
+        assert_eq!((stats.kept, stats.recent, stats.removed), (2, 1, 0));
+        let stats = store
+            .compact(&BTreeSet::from([kept]), Duration::ZERO)
+            .unwrap();
+        assert_eq!((stats.kept, stats.recent, stats.removed), (1, 0, 1));
+        assert!(!store.exists(dropped).unwrap());
+        assert_eq!(store.modified(kept).unwrap(), Some(written));
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(store.get(kept).unwrap(), Some(b"kept".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
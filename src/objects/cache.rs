@@ -0,0 +1,255 @@
+//least recently used cache in front of another store, bounded by a byte budget
+//
+//it keeps raw objects read through get_typed, and the values parsed from them
+//by read_json. streamed reads bypass it, so large files don't evict everything
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    io::{Cursor, Read},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use super::{ObjectKind, Objects, TypedContent};
+use crate::blob::{Blob, Hasher};
+
+pub struct CachedObjects<S> {
+    inner: S,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub parsed_hits: u64,
+    pub parsed_misses: u64,
+    pub evictions: u64,
+    pub bytes: usize,
+    pub budget: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Slot {
+    Raw,
+    Parsed,
+}
+
+enum Cached {
+    Raw(TypedContent),
+    Parsed(Arc<dyn Any + Send + Sync>),
+}
+
+struct Entry {
+    value: Cached,
+    size: usize,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: BTreeMap<(Blob, Slot), Entry>,
+    //entries by last use, oldest first
+    uses: BTreeMap<u64, (Blob, Slot)>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    fn get(&mut self, key: (Blob, Slot)) -> Option<&Cached> {
+        let entry = self.entries.get_mut(&key)?;
+        self.uses.remove(&entry.last_use);
+        self.tick += 1;
+        entry.last_use = self.tick;
+        self.uses.insert(self.tick, key);
+        Some(&entry.value)
+    }
+
+    fn insert(&mut self, key: (Blob, Slot), value: Cached, size: usize) {
+        //bigger than everything else together, it would only flush the cache
+        if size > self.stats.budget / 2 {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&key) {
+            self.uses.remove(&old.last_use);
+            self.stats.bytes -= old.size;
+        }
+        while self.stats.bytes + size > self.stats.budget {
+            let Some((_, oldest)) = self.uses.pop_first() else {
+                break;
+            };
+            if let Some(old) = self.entries.remove(&oldest) {
+                self.stats.bytes -= old.size;
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.uses.insert(self.tick, key);
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                last_use: self.tick,
+            },
+        );
+        self.stats.bytes += size;
+    }
+
+    fn remove(&mut self, key: (Blob, Slot)) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.uses.remove(&old.last_use);
+            self.stats.bytes -= old.size;
+        }
+    }
+}
+
+impl<S> CachedObjects<S> {
+    //`budget` is the number of object bytes kept in memory, 0 disables the cache
+    pub fn new(inner: S, budget: usize) -> Self {
+        let mut cache = Cache::default();
+        cache.stats.budget = budget;
+        CachedObjects {
+            inner,
+            cache: Mutex::new(cache),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache().stats.clone()
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, Cache> {
+        //the cache is left consistent between statements, so a panic elsewhere doesn't spoil it
+        self.cache.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<S: Objects> Objects for CachedObjects<S> {
+    type Error = S::Error;
+
+    fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
+        if self.cache().entries.contains_key(&(id, Slot::Raw)) {
+            return Ok(true);
+        }
+        self.inner.exists(id)
+    }
+
+    fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+        {
+            let mut cache = self.cache();
+            if let Some(Cached::Raw(content)) = cache.get((id, Slot::Raw)) {
+                let content = content.clone();
+                cache.stats.hits += 1;
+                return Ok(Some(content));
+            }
+            cache.stats.misses += 1;
+        }
+        let content = self.inner.get_typed(id)?;
+        if let Some(content) = &content {
+            self.cache().insert(
+                (id, Slot::Raw),
+                Cached::Raw(content.clone()),
+                content.1.len(),
+            );
+        }
+        Ok(content)
+    }
+
+    fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+        self.inner.push_typed(kind, object)
+    }
+
+    fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+        if let Some(Cached::Raw((kind, _))) = self.cache().get((id, Slot::Raw)) {
+            return Ok(Some(*kind));
+        }
+        self.inner.kind(id)
+    }
+
+    fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+        self.inner.push_reader(object)
+    }
+
+    fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+        self.inner.push_stored(id, kind, stored)
+    }
+
+    fn push_stored_reader(
+        &self,
+        kind: ObjectKind,
+        stored: &mut dyn Read,
+        id: &dyn Fn() -> Blob,
+    ) -> Result<Blob, Self::Error> {
+        self.inner.push_stored_reader(kind, stored, id)
+    }
+
+    fn hasher(&self) -> Hasher {
+        self.inner.hasher()
+    }
+
+    fn id_of(&self, content: &[u8]) -> Blob {
+        self.inner.id_of(content)
+    }
+
+    fn id_of_typed(&self, kind: ObjectKind, content: &[u8]) -> Blob {
+        self.inner.id_of_typed(kind, content)
+    }
+
+    fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+        self.inner.ids()
+    }
+
+    fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+        if let Some(Cached::Raw((_, v))) = self.cache().get((id, Slot::Raw)) {
+            return Ok(Some(v.len() as u64));
+        }
+        self.inner.size(id)
+    }
+
+    //deleted objects must not be served from memory afterwards
+    fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+        let mut cache = self.cache();
+        for id in ids {
+            cache.remove((*id, Slot::Raw));
+            cache.remove((*id, Slot::Parsed));
+        }
+        drop(cache);
+        self.inner.delete(ids)
+    }
+
+    fn modified(&self, id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+        self.inner.modified(id)
+    }
+
+    fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+        if let Some(Cached::Raw((_, v))) = self.cache().get((id, Slot::Raw)) {
+            return Ok(Some(Box::new(Cursor::new(v.clone()))));
+        }
+        self.inner.get_reader(id)
+    }
+
+    fn get_parsed(&self, id: Blob) -> Option<Arc<dyn Any + Send + Sync>> {
+        let mut cache = self.cache();
+        let value = match cache.get((id, Slot::Parsed)) {
+            Some(Cached::Parsed(value)) => Some(value.clone()),
+            _ => None,
+        };
+        match value {
+            Some(_) => cache.stats.parsed_hits += 1,
+            None => cache.stats.parsed_misses += 1,
+        }
+        value
+    }
+
+    fn put_parsed(&self, id: Blob, value: Arc<dyn Any + Send + Sync>, size: usize) {
+        self.cache()
+            .insert((id, Slot::Parsed), Cached::Parsed(value), size);
+    }
+}
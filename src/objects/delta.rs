@@ -0,0 +1,195 @@
+//delta encoding of an object against a similar base object
+//
+//a delta starts with the varint sizes of the base and of the result, followed by
+//instructions: COPY offset len (bytes taken from the base) or INSERT len bytes
+use std::{collections::HashMap, io::ErrorKind};
+
+use crate::encoding::{read_varint, write_varint};
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+//length of the windows of the base that are indexed for matching
+const BLOCK: usize = 16;
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+//encode `target` as a delta against `base`
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_varint(&mut delta, base.len() as u64);
+    write_varint(&mut delta, target.len() as u64);
+
+    //first offset of every block-aligned window of the base
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    let mut offset = 0;
+    while offset + BLOCK <= base.len() {
+        blocks
+            .entry(&base[offset..offset + BLOCK])
+            .or_insert(offset);
+        offset += BLOCK;
+    }
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let matched = if i + BLOCK <= target.len() {
+            blocks.get(&target[i..i + BLOCK]).copied()
+        } else {
+            None
+        };
+        match matched {
+            Some(start) => {
+                let mut len = BLOCK;
+                while start + len < base.len()
+                    && i + len < target.len()
+                    && base[start + len] == target[i + len]
+                {
+                    len += 1;
+                }
+                flush_insert(&mut delta, &mut pending);
+                delta.push(COPY);
+                write_varint(&mut delta, start as u64);
+                write_varint(&mut delta, len as u64);
+                i += len;
+            }
+            None => {
+                pending.push(target[i]);
+                i += 1;
+            }
+        }
+    }
+    flush_insert(&mut delta, &mut pending);
+    delta
+}
+
+fn flush_insert(delta: &mut Vec<u8>, pending: &mut Vec<u8>) {
+    if !pending.is_empty() {
+        delta.push(INSERT);
+        write_varint(delta, pending.len() as u64);
+        delta.append(pending);
+    }
+}
+
+//rebuild the target object from its base and delta
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut pos = 0;
+    let base_len = read_varint(delta, &mut pos)? as usize;
+    let target_len = read_varint(delta, &mut pos)? as usize;
+    if base_len != base.len() {
+        return Err(invalid("delta base has the wrong size"));
+    }
+    //every instruction takes at least 3 bytes of the delta and adds at most the
+    //whole base or the bytes it inserts
+    let instructions = delta.len() - pos;
+    let most = (instructions / 3)
+        .checked_mul(base.len().max(instructions))
+        .ok_or_else(|| invalid("delta result is too large"))?;
+    if target_len > most {
+        return Err(invalid("delta result is longer than the delta can produce"));
+    }
+
+    //a delta copying much of its base can still claim a size that doesn't fit in
+    //memory, so the target only grows as it is written
+    let mut target = Vec::with_capacity(target_len.min(base.len() + instructions));
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            COPY => {
+                let start = read_varint(delta, &mut pos)? as usize;
+                let len = read_varint(delta, &mut pos)? as usize;
+                let end = start
+                    .checked_add(len)
+                    .filter(|end| *end <= base.len())
+                    .ok_or_else(|| invalid("delta copies outside of its base"))?;
+                target.extend_from_slice(&base[start..end]);
+            }
+            INSERT => {
+                let len = read_varint(delta, &mut pos)? as usize;
+                let end = pos
+                    .checked_add(len)
+                    .filter(|end| *end <= delta.len())
+                    .ok_or_else(|| invalid("truncated delta insert"))?;
+                target.extend_from_slice(&delta[pos..end]);
+                pos = end;
+            }
+            _ => return Err(invalid("unknown delta instruction")),
+        }
+        if target.len() > target_len {
+            return Err(invalid("delta produced the wrong size"));
+        }
+    }
+
+    if target.len() != target_len {
+        return Err(invalid("delta produced the wrong size"));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed) ^ (i >> 7) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_similar_objects() {
+        let base = sample(10_000, 1);
+        let mut target = base.clone();
+        target.splice(4000..4100, b"changed in the middle".iter().copied());
+        target.extend_from_slice(b"and appended");
+        let delta = encode(&base, &target);
+        assert!(delta.len() < target.len() / 10);
+        assert_eq!(apply(&base, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn round_trips_unrelated_and_empty_objects() {
+        for (base, target) in [
+            (sample(100, 1), sample(300, 2)),
+            (Vec::new(), sample(50, 3)),
+            (sample(50, 4), Vec::new()),
+        ] {
+            assert_eq!(apply(&base, &encode(&base, &target)).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn rejects_a_base_of_another_size() {
+        let delta = encode(&sample(100, 1), &sample(100, 2));
+        assert!(apply(&sample(99, 1), &delta).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_the_delta_cannot_produce() {
+        let base = sample(100, 1);
+        for target_len in [u64::MAX, 1 << 40, 2] {
+            let mut delta = Vec::new();
+            write_varint(&mut delta, base.len() as u64);
+            write_varint(&mut delta, target_len);
+            delta.extend_from_slice(&[COPY, 0, 50]);
+            let err = apply(&base, &delta).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_copies_and_inserts_past_the_end() {
+        let base = sample(100, 1);
+        let mut copy = Vec::new();
+        write_varint(&mut copy, 100);
+        write_varint(&mut copy, 20);
+        copy.extend_from_slice(&[COPY, 90, 20]);
+        assert!(apply(&base, &copy).is_err());
+
+        let mut insert = Vec::new();
+        write_varint(&mut insert, 100);
+        write_varint(&mut insert, 5);
+        insert.extend_from_slice(&[INSERT, 5, 1, 2]);
+        assert!(apply(&base, &insert).is_err());
+    }
+}
@@ -1,59 +1,102 @@
 use std::{
-    fs::{create_dir, File},
-    io::{ErrorKind, Read, Write},
-    path::PathBuf,
+    collections::BTreeSet,
+    fs::{create_dir, create_dir_all, metadata, read_dir, remove_dir, remove_file, File},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::SystemTime,
 };
 
-use super::Objects;
-use crate::blob::Blob;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
-#[derive(Debug, Clone)]
+use super::{
+    pack::{Pack, PackStats},
+    ObjectKind, Objects, TypedContent,
+};
+use crate::{
+    atomic::{write_atomic, AtomicFile},
+    blob::{Blob, Hasher},
+    config::HashAlgorithm,
+};
+
+//compressed objects start with a header, followed by a zlib stream
+//objects without it were written uncompressed by older versions
+const COMPRESSED_HEADER: &[u8] = b"LOGZ\x01";
+//same, followed by one byte with the ObjectKind
+const TYPED_HEADER: &[u8] = b"LOGZ\x02";
+//bytes read at a time when streaming objects in
+pub(crate) const STREAM_BUFFER: usize = 64 * 1024;
+
+//content of a stored object as a stream, with its kind
+pub(crate) type TypedReader = (Option<ObjectKind>, Box<dyn Read>);
+
+#[derive(Debug)]
 pub struct DirectoryObjects {
     root: PathBuf,
+    hash: HashAlgorithm,
+    //replaced when objects are deleted, which only takes &self
+    packs: RwLock<Vec<Pack>>,
+}
+
+impl Clone for DirectoryObjects {
+    fn clone(&self) -> Self {
+        DirectoryObjects {
+            root: self.root.clone(),
+            hash: self.hash,
+            packs: RwLock::new(self.packs().clone()),
+        }
+    }
 }
 
 impl DirectoryObjects {
-    pub fn new(root: PathBuf) -> Result<Self, std::io::Error> {
+    pub fn new(root: PathBuf, hash: HashAlgorithm) -> Result<Self, std::io::Error> {
         if !root.join("objects").exists() {
             create_dir(root.join("objects"))?;
         }
-        Ok(Self { root })
+        let packs = Pack::open_all(&root.join("objects").join("pack"))?;
+        Ok(Self {
+            root,
+            hash,
+            packs: RwLock::new(packs),
+        })
     }
-}
 
-impl Objects for DirectoryObjects {
-    type Error = std::io::Error;
+    fn packs(&self) -> RwLockReadGuard<'_, Vec<Pack>> {
+        self.packs.read().unwrap_or_else(|err| err.into_inner())
+    }
 
-    fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
-        let blob_hash = format!("{}", id);
-        let blob_folder_name = &blob_hash[0..2];
-        let blob_filename = &blob_hash[2..];
-        let path_to_blob = self
-            .root
-            .join("objects")
-            .join(blob_folder_name)
-            .join(blob_filename);
-        if path_to_blob.exists() {
-            Ok(true)
-        } else {
-            Ok(false)
+    fn packs_mut(&self) -> RwLockWriteGuard<'_, Vec<Pack>> {
+        self.packs.write().unwrap_or_else(|err| err.into_inner())
+    }
+
+    //ids of the objects stored one per file under objects/xx/
+    pub fn loose_ids(&self) -> Result<Vec<Blob>, std::io::Error> {
+        let mut ids = Vec::new();
+        for folder in read_dir(self.root.join("objects"))? {
+            let folder = folder?;
+            let folder_name = folder.file_name().to_string_lossy().to_string();
+            if folder_name.len() != 2 || !folder.file_type()?.is_dir() {
+                continue;
+            }
+            for file in read_dir(folder.path())? {
+                let file_name = file?.file_name().to_string_lossy().to_string();
+                if let Some(id) = Blob::from_hex(&format!("{}{}", folder_name, file_name)) {
+                    ids.push(id);
+                }
+            }
         }
+        Ok(ids)
     }
 
-    fn get(&self, id: Blob) -> Result<Option<Vec<u8>>, Self::Error> {
-        let blob_hash = format!("{}", id);
-        let blob_folder_name = &blob_hash[0..2];
-        let blob_filename = &blob_hash[2..];
-        let path_to_blob = self
-            .root
-            .join("objects")
-            .join(blob_folder_name)
-            .join(blob_filename);
-        match std::fs::File::options().read(true).open(path_to_blob) {
+    pub fn get_loose(&self, id: Blob) -> Result<Option<TypedContent>, std::io::Error> {
+        match std::fs::File::options()
+            .read(true)
+            .open(self.object_path(id))
+        {
             Ok(mut file) => {
                 let mut v = Vec::new();
                 file.read_to_end(&mut v)?;
-                Ok(Some(v))
+                Ok(Some(decompress(v)?))
             }
             Err(err) => {
                 if err.kind() == ErrorKind::NotFound {
@@ -65,30 +108,411 @@ impl Objects for DirectoryObjects {
         }
     }
 
-    fn push(&mut self, object: &[u8]) -> Result<Blob, Self::Error> {
-        let blob = object.into();
-        let blob_hash = format!("{}", blob);
+    pub fn get_packed(&self, id: Blob) -> Result<Option<TypedContent>, std::io::Error> {
+        for pack in self.packs().iter() {
+            if let Some(v) = pack.get(id)? {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+
+    //the id is only known at the end, so compress into a temporary file meanwhile
+    //and hash the content with `hasher` if given
+    fn write_incoming(
+        &self,
+        kind: ObjectKind,
+        object: &mut dyn Read,
+        mut hasher: Option<&mut Hasher>,
+    ) -> Result<AtomicFile, std::io::Error> {
+        let file = AtomicFile::create(&self.root.join("objects").join("incoming"))?;
+        let mut encoder = compressor(Some(kind), file)?;
+        let mut buffer = vec![0u8; STREAM_BUFFER];
+        loop {
+            let n = match object.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..n]);
+            }
+            encoder.write_all(&buffer[..n])?;
+        }
+        encoder.finish()
+    }
+
+    //move an incoming object to its place, unless it is there already
+    fn commit_incoming(&self, mut file: AtomicFile, blob: Blob) -> Result<Blob, std::io::Error> {
+        if !self.exists(blob)? {
+            let path_to_blob_file = self.object_path(blob);
+            create_parent(&path_to_blob_file)?;
+            file.retarget(&path_to_blob_file);
+            file.commit()?;
+        }
+        Ok(blob)
+    }
+
+    //stream an object along with its kind, loose or packed
+    fn typed_reader(&self, id: Blob) -> Result<Option<TypedReader>, std::io::Error> {
+        match File::open(self.object_path(id)) {
+            Ok(file) => {
+                let len = file.metadata()?.len();
+                return typed_decompressor(file, 0, len).map(Some);
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        for pack in self.packs().iter() {
+            if let Some(reader) = pack.typed_reader(id)? {
+                return Ok(Some(reader));
+            }
+        }
+        Ok(None)
+    }
+
+    //compressed size, enough to order objects by size
+    fn stored_size(&self, id: Blob) -> Result<Option<u64>, std::io::Error> {
+        match metadata(self.object_path(id)) {
+            Ok(meta) => return Ok(Some(meta.len())),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        for pack in self.packs().iter() {
+            if let Some(size) = pack.stored_size(id)? {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn packed_ids(&self) -> BTreeSet<Blob> {
+        self.packs().iter().flat_map(|pack| pack.ids()).collect()
+    }
+
+    //move every loose object, and the content of older packs, into a single new pack
+    pub fn repack(&self) -> Result<PackStats, std::io::Error> {
+        let loose = self.loose_ids()?;
+        let mut ids = self.packed_ids();
+        ids.extend(loose.iter().copied());
+
+        let stats = self.replace_packs(ids)?;
+        for id in loose {
+            self.remove_loose(id)?;
+        }
+        Ok(stats)
+    }
+
+    //replace all packs with a single pack holding only `ids`
+    pub fn replace_packs(&self, ids: BTreeSet<Blob>) -> Result<PackStats, std::io::Error> {
+        if ids.is_empty() {
+            for old_pack in std::mem::take(&mut *self.packs_mut()) {
+                old_pack.remove()?;
+            }
+            return Ok(PackStats::default());
+        }
+
+        let pack_dir = self.root.join("objects").join("pack");
+        create_dir_all(&pack_dir)?;
+        let not_found = |id| std::io::Error::new(ErrorKind::NotFound, format!("{}", id));
+        let (new_pack, stats) = Pack::write(
+            &pack_dir,
+            ids.into_iter().collect(),
+            |id| self.stored_size(id)?.ok_or_else(|| not_found(id)),
+            |id| self.get_typed(id)?.ok_or_else(|| not_found(id)),
+            |id| self.typed_reader(id)?.ok_or_else(|| not_found(id)),
+        )?;
+
+        //everything is safely in the new pack now
+        let mut packs = self.packs_mut();
+        for old_pack in std::mem::take(&mut *packs) {
+            if !old_pack.same_files(&new_pack) {
+                old_pack.remove()?;
+            }
+        }
+        packs.push(new_pack);
+        Ok(stats)
+    }
+
+    pub fn remove_loose(&self, id: Blob) -> Result<(), std::io::Error> {
+        let path = self.object_path(id);
+        remove_file(&path)?;
+        if let Some(folder) = path.parent() {
+            //only succeeds once the folder is empty
+            let _ = remove_dir(folder);
+        }
+        Ok(())
+    }
+
+    //objects/xx/yyyy... where xxyyyy... is the hex of the blob
+    fn object_path(&self, id: Blob) -> PathBuf {
+        let blob_hash = format!("{}", id);
         let blob_folder_name = &blob_hash[0..2];
         let blob_filename = &blob_hash[2..];
-        let path_to_blob_folder = self
-            .root
+        self.root
             .join("objects")
-            .join(blob_folder_name);
-        let path_to_blob_file = path_to_blob_folder.join(blob_filename);
-
-        if path_to_blob_file.exists() {
-            Ok(blob)
-        } else {
-            if !path_to_blob_folder.exists() {
-                create_dir(path_to_blob_folder)?;
+            .join(blob_folder_name)
+            .join(blob_filename)
+    }
+}
+
+//objects of unknown kind keep the untyped header, so they stay unknown
+pub(crate) fn compress(kind: Option<ObjectKind>, object: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let header = match kind {
+        Some(kind) => [TYPED_HEADER, &[kind.to_byte()]].concat(),
+        None => COMPRESSED_HEADER.to_vec(),
+    };
+    let mut encoder = ZlibEncoder::new(header, Compression::default());
+    encoder.write_all(object)?;
+    encoder.finish()
+}
+
+//write the header of an object of `kind`, everything written afterwards is compressed
+pub(crate) fn compressor<W: Write>(
+    kind: Option<ObjectKind>,
+    mut out: W,
+) -> Result<ZlibEncoder<W>, std::io::Error> {
+    match kind {
+        Some(kind) => {
+            out.write_all(TYPED_HEADER)?;
+            out.write_all(&[kind.to_byte()])?;
+        }
+        None => out.write_all(COMPRESSED_HEADER)?,
+    }
+    Ok(ZlibEncoder::new(out, Compression::default()))
+}
+
+//kind and length of the header a stored object starts with, None for raw objects
+fn split_header(stored: &[u8]) -> Option<(Option<ObjectKind>, usize)> {
+    if let Some(rest) = stored.strip_prefix(TYPED_HEADER) {
+        let byte = rest.first()?;
+        Some((ObjectKind::from_byte(*byte), TYPED_HEADER.len() + 1))
+    } else if stored.starts_with(COMPRESSED_HEADER) {
+        Some((None, COMPRESSED_HEADER.len()))
+    } else {
+        None
+    }
+}
+
+//stream the content of the object stored in the `len` bytes of `file` from `start`
+//it is compressed by the same rule as in inflate, which a first pass checks by
+//inflating into nothing, so neither pass holds the object in memory
+pub(crate) fn decompressor(file: File, start: u64, len: u64) -> Result<Box<dyn Read>, std::io::Error> {
+    typed_decompressor(file, start, len).map(|(_, content)| content)
+}
+
+//the same, along with the kind of the object
+pub(crate) fn typed_decompressor(mut file: File, start: u64, len: u64) -> Result<TypedReader, std::io::Error> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut header = Vec::new();
+    Read::by_ref(&mut file)
+        .take(len.min(TYPED_HEADER.len() as u64 + 1))
+        .read_to_end(&mut header)?;
+    if let Some((kind, header_len)) = split_header(&header) {
+        let compressed = len - header_len as u64;
+        let zlib_start = start + header_len as u64;
+        file.seek(SeekFrom::Start(zlib_start))?;
+        let mut decoder = ZlibDecoder::new(Read::by_ref(&mut file).take(compressed));
+        let complete = std::io::copy(&mut decoder, &mut std::io::sink()).is_ok() && decoder.total_in() == compressed;
+        if complete {
+            file.seek(SeekFrom::Start(zlib_start))?;
+            return Ok((kind, Box::new(ZlibDecoder::new(file.take(compressed)))));
+        }
+    }
+    file.seek(SeekFrom::Start(start))?;
+    Ok((None, Box::new(file.take(len))))
+}
+
+//objects written raw by older versions may start like a header, so an object
+//only counts as compressed when all that follows the header is a complete zlib stream
+pub(crate) fn decompress(stored: Vec<u8>) -> Result<TypedContent, std::io::Error> {
+    match inflate(&stored) {
+        Some(content) => Ok(content),
+        None => Ok((None, stored)),
+    }
+}
+
+//the content of an object written with a header, None if it wasn't or is damaged
+pub(crate) fn inflate(stored: &[u8]) -> Option<TypedContent> {
+    let (kind, header_len) = split_header(stored)?;
+    let compressed = &stored[header_len..];
+    let mut decoder = ZlibDecoder::new(compressed);
+    let mut v = Vec::new();
+    decoder.read_to_end(&mut v).ok()?;
+    (decoder.total_in() == compressed.len() as u64).then_some((kind, v))
+}
+
+impl Objects for DirectoryObjects {
+    type Error = std::io::Error;
+
+    fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
+        Ok(self.object_path(id).exists() || self.packs().iter().any(|pack| pack.contains(id)))
+    }
+
+    fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+        match self.get_loose(id)? {
+            Some(v) => Ok(Some(v)),
+            None => self.get_packed(id),
+        }
+    }
+
+    fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+        let blob = self.id_of_typed(kind, object);
+        self.push_stored(blob, kind, object)?;
+        Ok(blob)
+    }
+
+    fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+        let path_to_blob_file = self.object_path(id);
+
+        if !self.exists(id)? {
+            create_parent(&path_to_blob_file)?;
+            write_atomic(&path_to_blob_file, &compress(Some(kind), stored)?)?;
+        }
+        Ok(())
+    }
+
+    fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+        let mut hasher = self.hasher();
+        let file = self.write_incoming(ObjectKind::Blob, object, Some(&mut hasher))?;
+        self.commit_incoming(file, hasher.finalize())
+    }
+
+    fn push_stored_reader(
+        &self,
+        kind: ObjectKind,
+        stored: &mut dyn Read,
+        id: &dyn Fn() -> Blob,
+    ) -> Result<Blob, Self::Error> {
+        let file = self.write_incoming(kind, stored, None)?;
+        self.commit_incoming(file, id())
+    }
+
+    fn hasher(&self) -> Hasher {
+        Hasher::new(self.hash)
+    }
+
+    fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+        Ok(self.typed_reader(id)?.map(|(_, content)| content))
+    }
+
+    fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+        Ok(self.typed_reader(id)?.map(|(kind, _)| kind))
+    }
+
+    fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+        let mut ids = self.packed_ids();
+        ids.extend(self.loose_ids()?);
+        Ok(ids)
+    }
+
+    fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+        match File::open(self.object_path(id)) {
+            Ok(file) => {
+                let len = file.metadata()?.len();
+                Ok(Some(std::io::copy(
+                    &mut decompressor(file, 0, len)?,
+                    &mut std::io::sink(),
+                )?))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Ok(self.get_packed(id)?.map(|(_, v)| v.len() as u64))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    //loose objects are removed one by one, packs are rewritten once without the deleted ones
+    fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+        let mut deleted = BTreeSet::new();
+        for id in ids {
+            if self.object_path(*id).exists() {
+                self.remove_loose(*id)?;
+                deleted.insert(*id);
+            }
+        }
+        let packed = self.packed_ids();
+        if ids.iter().any(|id| packed.contains(id)) {
+            self.replace_packs(packed.difference(ids).copied().collect())?;
+            deleted.extend(packed.intersection(ids));
+        }
+        Ok(deleted.len())
+    }
+
+    //its own file if loose, otherwise the pack holding it
+    fn modified(&self, id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+        match metadata(self.object_path(id)) {
+            Ok(meta) => return Ok(Some(meta.modified()?)),
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            Err(_) => {}
+        }
+        for pack in self.packs().iter() {
+            if pack.contains(id) {
+                return pack.modified().map(Some);
             }
-            let mut file = File::options()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(path_to_blob_file)?;
-            file.write_all(object)?;
-            Ok(blob)
         }
+        Ok(None)
+    }
+}
+
+fn create_parent(path: &Path) -> Result<(), std::io::Error> {
+    match path.parent() {
+        Some(folder) => create_dir_all(folder),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> DirectoryObjects {
+        let root = std::env::temp_dir().join(format!("log-objects-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        DirectoryObjects::new(root, HashAlgorithm::Blake3).unwrap()
+    }
+
+    //as written by versions that stored objects raw
+    fn push_raw(store: &DirectoryObjects, content: &[u8]) -> Blob {
+        let id = store.id_of(content);
+        let path = store.object_path(id);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        id
+    }
+
+    fn streamed(store: &DirectoryObjects, id: Blob) -> Vec<u8> {
+        let mut content = Vec::new();
+        store.get_reader(id).unwrap().unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn raw_objects_that_look_compressed_read_the_same_both_ways() {
+        let store = temp_store("raw");
+        //a valid zlib header without the stream behind it
+        let truncated = [COMPRESSED_HEADER, &[0x78, 0x9c, 1, 2, 3]].concat();
+        //a complete stream with more after it
+        let trailing = [compress(None, b"content").unwrap(), b"more".to_vec()].concat();
+        for content in [truncated, trailing] {
+            let id = push_raw(&store, &content);
+            assert_eq!(store.get_typed(id).unwrap(), Some((None, content.clone())));
+            assert_eq!(streamed(&store, id), content);
+        }
+        std::fs::remove_dir_all(&store.root).unwrap();
+    }
+
+    #[test]
+    fn compressed_objects_read_the_same_both_ways() {
+        let store = temp_store("compressed");
+        let id = store.push_typed(ObjectKind::Tree, b"tree content").unwrap();
+        assert_eq!(
+            store.get_typed(id).unwrap(),
+            Some((Some(ObjectKind::Tree), b"tree content".to_vec()))
+        );
+        assert_eq!(streamed(&store, id), b"tree content");
+        std::fs::remove_dir_all(&store.root).unwrap();
     }
 }
@@ -0,0 +1,494 @@
+//objects encrypted before they reach the store below
+//
+//content is sealed with XChaCha20-Poly1305 in segments of SEGMENT_LEN bytes,
+//following the STREAM construction: a random nonce prefix is stored first, and
+//every segment is sealed under that prefix, its position and whether it is the
+//last one, then stored with its tag. objects are sealed and opened a segment at
+//a time, so they stream both ways, and a cut off object fails to open. ids are hashed with a key derived from the
+//repository key, keyed blake3 or HMAC-SHA256 following the hash algorithm of the
+//repository, so they don't reveal the content either. the kind and
+//the size of each object are still visible in the store below
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    fs::{read_to_string, File},
+    io::{ErrorKind, Read, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use chacha20poly1305::{
+    aead::{
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit,
+    },
+    Key, XChaCha20Poly1305,
+};
+
+use super::{ObjectKind, Objects, TypedContent};
+use crate::{
+    blob::{Blob, Hasher},
+    config::HashAlgorithm,
+    hex::Hex,
+};
+
+//environment variable holding the key in hex, used before the key file
+pub const KEY_VARIABLE: &str = "LOG_ENCRYPTION_KEY";
+
+//the STREAM counter and last segment flag take the other 5 bytes of the nonce
+const NONCE_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+const SEGMENT_LEN: usize = 64 * 1024;
+const CIPHER_CONTEXT: &str = "log 2024 object encryption";
+const ID_CONTEXT: &str = "log 2024 object ids";
+const CHECK_CONTEXT: &str = "log 2024 key check";
+
+pub struct EncryptedObjects<S> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+    hasher: Hasher,
+}
+
+impl<S> EncryptedObjects<S> {
+    pub fn new(inner: S, key: &[u8; 32], hash: HashAlgorithm) -> Self {
+        let cipher_key = blake3::derive_key(CIPHER_CONTEXT, key);
+        EncryptedObjects {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&cipher_key)),
+            hasher: Hasher::keyed(hash, &blake3::derive_key(ID_CONTEXT, key)),
+        }
+    }
+
+    //kept in the config to tell a wrong key apart from damaged objects
+    pub fn key_check(key: &[u8; 32]) -> Blob {
+        Blob::from_bytes(blake3::derive_key(CHECK_CONTEXT, key))
+    }
+
+    fn encrypt(&self, content: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut sealed = Vec::new();
+        Sealer::new(&self.cipher, content)?.read_to_end(&mut sealed)?;
+        Ok(sealed)
+    }
+
+    fn decrypt(&self, stored: &[u8]) -> Option<Vec<u8>> {
+        let mut content = Vec::new();
+        Opener::new(&self.cipher, stored)
+            .ok()?
+            .read_to_end(&mut content)
+            .ok()?;
+        Some(content)
+    }
+}
+
+//the sealed form of what is read from `source`
+struct Sealer<R> {
+    source: R,
+    //None once the last segment is sealed
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    //content read but not sealed yet, up to a segment and one byte to tell
+    //whether more follows
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Sealer<R> {
+    fn new(cipher: &XChaCha20Poly1305, source: R) -> Result<Self, std::io::Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)?;
+        Ok(Sealer {
+            source,
+            encryptor: Some(EncryptorBE32::from_aead(cipher.clone(), nonce.as_slice().into())),
+            pending: Vec::with_capacity(SEGMENT_LEN + 1),
+            out: nonce.to_vec(),
+            pos: 0,
+        })
+    }
+
+    fn seal_next(&mut self) -> Result<(), std::io::Error> {
+        fill(&mut self.source, &mut self.pending, SEGMENT_LEN + 1)?;
+        let sealed = match self.encryptor.take() {
+            Some(mut encryptor) if self.pending.len() > SEGMENT_LEN => {
+                let sealed = encryptor.encrypt_next(&self.pending[..SEGMENT_LEN]);
+                self.encryptor = Some(encryptor);
+                self.pending.drain(..SEGMENT_LEN);
+                sealed
+            }
+            Some(encryptor) => encryptor.encrypt_last(self.pending.as_slice()),
+            None => return Ok(()),
+        };
+        self.out = sealed.map_err(|_| invalid("cannot encrypt object"))?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Sealer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.out.len() && self.encryptor.is_some() {
+            self.seal_next()?;
+        }
+        Ok(hand_out(&self.out, &mut self.pos, buf))
+    }
+}
+
+//the content of the sealed form read from `source`
+struct Opener<R> {
+    source: R,
+    //None once the last segment is opened
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    //sealed segment read but not opened yet, and one byte more
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Opener<R> {
+    fn new(cipher: &XChaCha20Poly1305, mut source: R) -> Result<Self, std::io::Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        source
+            .read_exact(&mut nonce)
+            .map_err(|_| invalid("cannot decrypt object"))?;
+        Ok(Opener {
+            source,
+            decryptor: Some(DecryptorBE32::from_aead(cipher.clone(), nonce.as_slice().into())),
+            pending: Vec::with_capacity(SEGMENT_LEN + TAG_LEN + 1),
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn open_next(&mut self) -> Result<(), std::io::Error> {
+        let sealed_len = SEGMENT_LEN + TAG_LEN;
+        fill(&mut self.source, &mut self.pending, sealed_len + 1)?;
+        let content = match self.decryptor.take() {
+            Some(mut decryptor) if self.pending.len() > sealed_len => {
+                let content = decryptor.decrypt_next(&self.pending[..sealed_len]);
+                self.decryptor = Some(decryptor);
+                self.pending.drain(..sealed_len);
+                content
+            }
+            Some(decryptor) => decryptor.decrypt_last(self.pending.as_slice()),
+            None => return Ok(()),
+        };
+        self.out = content.map_err(|_| invalid("cannot decrypt object"))?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        //segments are never empty but the last one
+        while self.pos == self.out.len() && self.decryptor.is_some() {
+            self.open_next()?;
+        }
+        Ok(hand_out(&self.out, &mut self.pos, buf))
+    }
+}
+
+//read until `buffer` holds `len` bytes or `source` ends
+fn fill(source: &mut impl Read, buffer: &mut Vec<u8>, len: usize) -> Result<(), std::io::Error> {
+    let missing = len.saturating_sub(buffer.len()) as u64;
+    source.take(missing).read_to_end(buffer)?;
+    Ok(())
+}
+
+//copy what `buf` takes of `out` from `pos` on
+fn hand_out(out: &[u8], pos: &mut usize, buf: &mut [u8]) -> usize {
+    let n = buf.len().min(out.len() - *pos);
+    buf[..n].copy_from_slice(&out[*pos..*pos + n]);
+    *pos += n;
+    n
+}
+
+//content read from `source`, hashed on the way into `hasher`
+struct Hashing<'a, R> {
+    source: R,
+    hasher: &'a RefCell<Hasher>,
+}
+
+impl<R: Read> Read for Hashing<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.source.read(buf)?;
+        self.hasher.borrow_mut().update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+//a streamed blob, which fails at its end unless its content has `id`
+struct Verified<R> {
+    source: R,
+    hasher: Hasher,
+    id: Blob,
+}
+
+impl<R: Read> Read for Verified<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.source.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.hasher.finalize() != self.id {
+            return Err(invalid("decrypted object doesn't match its id"));
+        }
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+//the key from KEY_VARIABLE, or else from `key_file`, checked against `check`
+pub fn load_key(key_file: Option<&Path>, check: Blob) -> Result<[u8; 32], std::io::Error> {
+    let hex = match std::env::var(KEY_VARIABLE) {
+        Ok(hex) => hex,
+        Err(_) => match key_file {
+            Some(path) => read_to_string(path).map_err(|err| match err.kind() {
+                ErrorKind::NotFound => std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("key file {} is missing, set {} to the key", path.display(), KEY_VARIABLE),
+                ),
+                _ => err,
+            })?,
+            None => {
+                return Err(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("the repository is encrypted, set {} to its key", KEY_VARIABLE),
+                ))
+            }
+        },
+    };
+    let key = parse_key(&hex)?;
+    if EncryptedObjects::<()>::key_check(&key) != check {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "wrong encryption key for this repository",
+        ));
+    }
+    Ok(key)
+}
+
+//64 hex digits, surrounding whitespace is ignored
+pub fn parse_key(hex: &str) -> Result<[u8; 32], std::io::Error> {
+    let hex = hex.trim().as_bytes();
+    if hex.len() != 64 {
+        return Err(invalid("an encryption key is 64 hex digits"));
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid("invalid encryption key"))?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid("invalid encryption key"))?;
+    }
+    Ok(key)
+}
+
+//key for a new repository: from KEY_VARIABLE, else read from `key_file`, which
+//is created with a random key when missing
+pub fn init_key(key_file: Option<&Path>) -> Result<[u8; 32], std::io::Error> {
+    if let Ok(hex) = std::env::var(KEY_VARIABLE) {
+        return parse_key(&hex);
+    }
+    let path = match key_file {
+        Some(path) => path,
+        None => {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("encryption needs a key file or {}", KEY_VARIABLE),
+            ))
+        }
+    };
+    if path.exists() {
+        return parse_key(&read_to_string(path)?);
+    }
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key)?;
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", Hex::from(&key[..]))?;
+    file.sync_all()?;
+    Ok(key)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+impl<S: Objects> Objects for EncryptedObjects<S>
+where
+    S::Error: From<std::io::Error>,
+{
+    type Error = S::Error;
+
+    fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
+        self.inner.exists(id)
+    }
+
+    //kinds are stored in the clear
+    fn kind(&self, id: Blob) -> Result<Option<Option<ObjectKind>>, Self::Error> {
+        self.inner.kind(id)
+    }
+
+    fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+        let (kind, stored) = match self.inner.get_typed(id)? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let content = match self.decrypt(&stored) {
+            Some(content) => content,
+            None => return Err(invalid("cannot decrypt object").into()),
+        };
+        if !self.is_id_of(id, kind, &content) {
+            return Err(invalid("decrypted object doesn't match its id").into());
+        }
+        Ok(Some((kind, content)))
+    }
+
+    fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+        let blob = self.id_of_typed(kind, object);
+        if !self.inner.exists(blob)? {
+            self.inner.push_stored(blob, kind, &self.encrypt(object)?)?;
+        }
+        Ok(blob)
+    }
+
+    //sealed while it is read, the store below gets the id once the content is hashed
+    fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+        let hasher = RefCell::new(self.hasher());
+        let content = Hashing {
+            source: object,
+            hasher: &hasher,
+        };
+        self.inner.push_stored_reader(
+            ObjectKind::Blob,
+            &mut Sealer::new(&self.cipher, content)?,
+            &|| hasher.borrow().finalize(),
+        )
+    }
+
+    //opened while it is read. only blobs are streamed, whose id is the hash of
+    //their content, which is checked at the end
+    fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+        let stored = match self.inner.get_reader(id)? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        Ok(Some(Box::new(Verified {
+            source: Opener::new(&self.cipher, stored)?,
+            hasher: self.hasher(),
+            id,
+        })))
+    }
+
+    fn hasher(&self) -> Hasher {
+        self.hasher.clone()
+    }
+
+    fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+        if !self.inner.exists(id)? {
+            self.inner.push_stored(id, kind, &self.encrypt(stored)?)?;
+        }
+        Ok(())
+    }
+
+    fn push_stored_reader(
+        &self,
+        kind: ObjectKind,
+        stored: &mut dyn Read,
+        id: &dyn Fn() -> Blob,
+    ) -> Result<Blob, Self::Error> {
+        self.inner
+            .push_stored_reader(kind, &mut Sealer::new(&self.cipher, stored)?, id)
+    }
+
+    fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+        self.inner.ids()
+    }
+
+    //the nonce and the tag of every segment are not part of the content
+    fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+        Ok(self.inner.size(id)?.map(|size| {
+            let segments = size.saturating_sub(NONCE_LEN as u64);
+            let count = segments.div_ceil((SEGMENT_LEN + TAG_LEN) as u64).max(1);
+            segments.saturating_sub(count * TAG_LEN as u64)
+        }))
+    }
+
+    fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+        self.inner.delete(ids)
+    }
+
+    fn modified(&self, id: Blob) -> Result<Option<SystemTime>, Self::Error> {
+        self.inner.modified(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::memory::InMemoryBlobStore;
+
+    fn store() -> EncryptedObjects<InMemoryBlobStore> {
+        EncryptedObjects::new(InMemoryBlobStore::new(), &[7; 32], HashAlgorithm::Blake3)
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    //empty, one segment, a segment and a byte, several segments
+    const LENGTHS: [usize; 5] = [0, 1, SEGMENT_LEN, SEGMENT_LEN + 1, 3 * SEGMENT_LEN + 5];
+
+    #[test]
+    fn streamed_objects_round_trip() {
+        let store = store();
+        for len in LENGTHS {
+            let content = sample(len);
+            let id = store.push_reader(&mut content.as_slice()).unwrap();
+            assert_eq!(id, store.id_of(&content));
+            let mut read = Vec::new();
+            store.get_reader(id).unwrap().unwrap().read_to_end(&mut read).unwrap();
+            assert_eq!(read, content);
+            assert_eq!(store.get(id).unwrap(), Some(content));
+            assert_eq!(store.size(id).unwrap(), Some(len as u64));
+        }
+    }
+
+    #[test]
+    fn stored_objects_are_sealed() {
+        let store = store();
+        let content = sample(SEGMENT_LEN + 1);
+        let id = store.push(&content).unwrap();
+        let stored = store.inner.get(id).unwrap().unwrap();
+        assert_eq!(stored.len(), NONCE_LEN + content.len() + 2 * TAG_LEN);
+        assert!(!stored.windows(64).any(|window| window == &content[..64]));
+    }
+
+    #[test]
+    fn cut_off_objects_fail_to_open() {
+        let store = store();
+        let content = sample(2 * SEGMENT_LEN + 1);
+        let id = store.push(&content).unwrap();
+        let stored = store.inner.get(id).unwrap().unwrap();
+        //only whole segments are left, the last of them isn't marked as last
+        let cut = &stored[..NONCE_LEN + 2 * (SEGMENT_LEN + TAG_LEN)];
+        assert!(store.decrypt(cut).is_none());
+        assert!(store.decrypt(&stored[..stored.len() - 1]).is_none());
+        assert_eq!(store.decrypt(&stored), Some(content));
+    }
+
+    #[test]
+    fn streamed_objects_under_another_id_fail() {
+        let store = store();
+        let content = sample(10);
+        let id = store.push(&content).unwrap();
+        let other = Blob::from_bytes([1; 32]);
+        let stored = store.inner.get(id).unwrap().unwrap();
+        store.inner.push_stored(other, ObjectKind::Blob, &stored).unwrap();
+        let mut read = Vec::new();
+        assert!(store
+            .get_reader(other)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut read)
+            .is_err());
+    }
+}
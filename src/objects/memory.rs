@@ -1,45 +1,108 @@
-use crate::blob::Blob;
+use crate::{
+    blob::{Blob, Hasher},
+    config::HashAlgorithm,
+};
 
-use super::Objects;
+use super::{ObjectKind, Objects, TypedContent};
 
-use ::std::collections::{btree_map::Entry, BTreeMap};
-use std::convert::Infallible;
+use ::std::collections::{BTreeMap, BTreeSet};
+use std::{
+    io::{Cursor, Read},
+    sync::{RwLock, RwLockReadGuard},
+};
 
 pub struct InMemoryBlobStore {
-    blobs: BTreeMap<Blob, Vec<u8>>,
+    hash: HashAlgorithm,
+    blobs: RwLock<BTreeMap<Blob, (ObjectKind, Vec<u8>)>>,
 }
 
 impl InMemoryBlobStore {
     pub fn new() -> Self {
+        Self::with_hash(HashAlgorithm::default())
+    }
+
+    pub fn with_hash(hash: HashAlgorithm) -> Self {
         Self {
-            blobs: BTreeMap::new(),
+            hash,
+            blobs: RwLock::new(BTreeMap::new()),
         }
     }
+
+    fn blobs(&self) -> RwLockReadGuard<'_, BTreeMap<Blob, (ObjectKind, Vec<u8>)>> {
+        self.blobs.read().unwrap_or_else(|err| err.into_inner())
+    }
 }
 
 impl Objects for InMemoryBlobStore {
-    type Error = Infallible;
+    type Error = std::io::Error;
 
     fn exists(&self, id: Blob) -> Result<bool, Self::Error> {
-        Ok(self.blobs.contains_key(&id))
+        Ok(self.blobs().contains_key(&id))
     }
 
-    fn get(&self, id: Blob) -> Result<Option<Vec<u8>>, Self::Error> {
-        match self.blobs.get(&id) {
-            Some(v) => Ok(Some(v.clone())),
+    fn get_typed(&self, id: Blob) -> Result<Option<TypedContent>, Self::Error> {
+        match self.blobs().get(&id) {
+            Some((kind, v)) => Ok(Some((Some(*kind), v.clone()))),
             None => Ok(None),
         }
     }
 
-    fn push(&mut self, object: &[u8]) -> Result<Blob, Self::Error> {
-        let blob = object.into();
-        match self.blobs.entry(blob) {
-            Entry::Vacant(v) => {
-                v.insert(object.into());
-                Ok(blob)
-            }
-            Entry::Occupied(_o) => Ok(blob),
-        }
+    fn push_typed(&self, kind: ObjectKind, object: &[u8]) -> Result<Blob, Self::Error> {
+        let blob = self.id_of_typed(kind, object);
+        self.push_stored(blob, kind, object)?;
+        Ok(blob)
+    }
+
+    fn push_stored(&self, id: Blob, kind: ObjectKind, stored: &[u8]) -> Result<(), Self::Error> {
+        self.blobs
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(id)
+            .or_insert_with(|| (kind, stored.into()));
+        Ok(())
+    }
+
+    fn push_stored_reader(
+        &self,
+        kind: ObjectKind,
+        stored: &mut dyn Read,
+        id: &dyn Fn() -> Blob,
+    ) -> Result<Blob, Self::Error> {
+        let mut v = Vec::new();
+        stored.read_to_end(&mut v)?;
+        let blob = id();
+        self.push_stored(blob, kind, &v)?;
+        Ok(blob)
+    }
+
+    fn push_reader(&self, object: &mut dyn Read) -> Result<Blob, Self::Error> {
+        let mut v = Vec::new();
+        object.read_to_end(&mut v)?;
+        self.push(&v)
+    }
+
+    fn hasher(&self) -> Hasher {
+        Hasher::new(self.hash)
+    }
+
+    fn get_reader(&self, id: Blob) -> Result<Option<Box<dyn Read>>, Self::Error> {
+        Ok(self
+            .blobs()
+            .get(&id)
+            .map(|(_, v)| Box::new(Cursor::new(v.clone())) as Box<dyn Read>))
+    }
+
+    fn ids(&self) -> Result<BTreeSet<Blob>, Self::Error> {
+        Ok(self.blobs().keys().copied().collect())
+    }
+
+    fn size(&self, id: Blob) -> Result<Option<u64>, Self::Error> {
+        Ok(self.blobs().get(&id).map(|(_, v)| v.len() as u64))
+    }
+
+    fn delete(&self, ids: &BTreeSet<Blob>) -> Result<usize, Self::Error> {
+        let mut blobs = self.blobs.write().unwrap_or_else(|err| err.into_inner());
+        Ok(ids.iter().filter(|id| blobs.remove(id).is_some()).count())
     }
 }
 
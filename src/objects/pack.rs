@@ -0,0 +1,498 @@
+//packfile plus index, holding objects moved out of objects/xx/ by repack
+//
+//pack-<name>.pack: header, then one entry per object:
+//  kind (FULL or DELTA), base id if DELTA, u64 length, compressed payload
+//  the payload header records the ObjectKind, also for deltas
+//pack-<name>.idx: header, u64 count, then (id, u64 offset) sorted by id
+//
+//the index is written only after its pack is complete, so a pack
+//without an index is ignored. repack replaces all packs with a single one.
+use std::{
+    collections::BTreeMap,
+    fs::{metadata, read_dir, remove_file, File},
+    io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use super::{
+    delta,
+    directory::{compress, compressor, decompress, typed_decompressor, TypedReader},
+    TypedContent,
+};
+use crate::{
+    atomic::{write_atomic, AtomicFile},
+    blob::Blob,
+};
+
+const PACK_HEADER: &[u8] = b"LOGPACK\x01";
+const INDEX_HEADER: &[u8] = b"LOGIDX\x01";
+
+const FULL: u8 = 0;
+const DELTA: u8 = 1;
+
+//how many of the previously packed objects are tried as delta bases
+const WINDOW: usize = 10;
+//longest chain of deltas that has to be resolved to read an object
+const MAX_DEPTH: usize = 10;
+//objects stored at least this large are streamed into the pack whole, they are
+//neither deltas nor delta bases, so the window never holds them
+const STREAM_MIN: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Pack {
+    pack_path: PathBuf,
+    index_path: PathBuf,
+    //sorted by id
+    index: Vec<(Blob, u64)>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PackStats {
+    pub objects: usize,
+    pub deltas: usize,
+    pub pack_size: u64,
+}
+
+impl Pack {
+    //open every complete pack in `dir`
+    pub fn open_all(dir: &Path) -> Result<Vec<Self>, std::io::Error> {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut packs = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "idx") {
+                packs.push(Pack::open(&path)?);
+            }
+        }
+        packs.sort_by(|a, b| a.index_path.cmp(&b.index_path));
+        Ok(packs)
+    }
+
+    pub fn open(index_path: &Path) -> Result<Self, std::io::Error> {
+        let mut file = File::open(index_path)?;
+        let mut v = Vec::new();
+        file.read_to_end(&mut v)?;
+        let data = v
+            .strip_prefix(INDEX_HEADER)
+            .ok_or_else(|| invalid("bad pack index header"))?;
+        //checked against the length before anything is allocated for it
+        let count = read_u64(data, 0)?;
+        if count.checked_mul(40) != Some(data.len() as u64 - 8) {
+            return Err(invalid("pack index length doesn't match its count"));
+        }
+        let count = count as usize;
+        let mut index = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = 8 + i * 40;
+            let id = read_id(data, start)?;
+            let offset = read_u64(data, start + 32)?;
+            index.push((id, offset));
+        }
+        Ok(Pack {
+            pack_path: index_path.with_extension("pack"),
+            index_path: index_path.to_path_buf(),
+            index,
+        })
+    }
+
+    //delete the pack and its index
+    pub fn remove(self) -> Result<(), std::io::Error> {
+        remove_file(&self.index_path)?;
+        remove_file(&self.pack_path)
+    }
+
+    pub fn modified(&self) -> Result<SystemTime, std::io::Error> {
+        metadata(&self.pack_path)?.modified()
+    }
+
+    pub fn contains(&self, id: Blob) -> bool {
+        self.offset(id).is_some()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = Blob> + '_ {
+        self.index.iter().map(|(id, _)| *id)
+    }
+
+    fn offset(&self, id: Blob) -> Option<u64> {
+        self.index
+            .binary_search_by(|(entry, _)| entry.cmp(&id))
+            .ok()
+            .map(|i| self.index[i].1)
+    }
+
+    pub fn get(&self, id: Blob) -> Result<Option<TypedContent>, std::io::Error> {
+        match self.offset(id) {
+            None => Ok(None),
+            Some(offset) => {
+                let mut file = File::open(&self.pack_path)?;
+                self.read_entry(&mut file, offset, 0).map(Some)
+            }
+        }
+    }
+
+    fn read_entry(
+        &self,
+        file: &mut File,
+        offset: u64,
+        depth: usize,
+    ) -> Result<TypedContent, std::io::Error> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("pack delta chain is too deep"));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut kind = [0u8; 1];
+        file.read_exact(&mut kind)?;
+        let base = if kind[0] == DELTA {
+            let mut id = [0u8; 32];
+            file.read_exact(&mut id)?;
+            Some(Blob::from_bytes(id))
+        } else if kind[0] == FULL {
+            None
+        } else {
+            return Err(invalid("unknown pack entry kind"));
+        };
+        let mut len = [0u8; 8];
+        file.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        //checked against the pack before anything is allocated for it
+        let left = file.metadata()?.len().saturating_sub(file.stream_position()?);
+        if len > left {
+            return Err(invalid("pack entry is longer than the pack"));
+        }
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload)?;
+        let (kind, payload) = decompress(payload)?;
+
+        match base {
+            None => Ok((kind, payload)),
+            Some(base) => {
+                let base_offset = self
+                    .offset(base)
+                    .ok_or_else(|| invalid("pack delta base is missing"))?;
+                let (_, base) = self.read_entry(file, base_offset, depth + 1)?;
+                Ok((kind, delta::apply(&base, &payload)?))
+            }
+        }
+    }
+
+    //stream an object, full entries are read from the pack as they are needed,
+    //deltas are rebuilt in memory
+    pub fn typed_reader(&self, id: Blob) -> Result<Option<TypedReader>, std::io::Error> {
+        let Some(offset) = self.offset(id) else {
+            return Ok(None);
+        };
+        let mut file = File::open(&self.pack_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut kind = [0u8; 1];
+        file.read_exact(&mut kind)?;
+        if kind[0] != FULL {
+            let (kind, content) = self.read_entry(&mut file, offset, 0)?;
+            return Ok(Some((kind, Box::new(std::io::Cursor::new(content)))));
+        }
+        let mut len = [0u8; 8];
+        file.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        let start = offset + 9;
+        if len > file.metadata()?.len().saturating_sub(start) {
+            return Err(invalid("pack entry is longer than the pack"));
+        }
+        typed_decompressor(file, start, len).map(Some)
+    }
+
+    //stored size of an object, without reading it
+    pub fn stored_size(&self, id: Blob) -> Result<Option<u64>, std::io::Error> {
+        let Some(offset) = self.offset(id) else {
+            return Ok(None);
+        };
+        let mut file = File::open(&self.pack_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut kind = [0u8; 1];
+        file.read_exact(&mut kind)?;
+        if kind[0] == DELTA {
+            file.seek(SeekFrom::Current(32))?;
+        }
+        let mut len = [0u8; 8];
+        file.read_exact(&mut len)?;
+        Ok(Some(u64::from_le_bytes(len)))
+    }
+
+    //write a new pack into `dir` holding `ids`
+    //objects are loaded once, one at a time through `load`, so only the delta window
+    //stays in memory. they are ordered by `size`, which shouldn't have to read them.
+    //objects of at least STREAM_MIN are copied through `open` instead
+    pub fn write<S, F, O>(
+        dir: &Path,
+        ids: Vec<Blob>,
+        size: S,
+        load: F,
+        open: O,
+    ) -> Result<(Pack, PackStats), std::io::Error>
+    where
+        S: Fn(Blob) -> Result<u64, std::io::Error>,
+        F: Fn(Blob) -> Result<TypedContent, std::io::Error>,
+        O: Fn(Blob) -> Result<TypedReader, std::io::Error>,
+    {
+        //packs are named after their objects, so packing the same objects twice gives the same pack
+        let mut names = Vec::with_capacity(ids.len() * 32);
+        for id in &ids {
+            names.extend_from_slice(id.as_bytes());
+        }
+        let name = format!("{}", Blob::checksum(&names));
+        let pack_path = dir.join(format!("pack-{}.pack", &name[..16]));
+        let index_path = pack_path.with_extension("idx");
+
+        //bigger objects first, so that later similar objects are deltas against them
+        let mut sized = Vec::with_capacity(ids.len());
+        for id in ids {
+            sized.push((size(id)?, id));
+        }
+        sized.sort_by(|a, b| b.cmp(a));
+
+        let mut out = BufWriter::new(AtomicFile::create(&pack_path)?);
+        out.write_all(PACK_HEADER)?;
+        let mut offset = PACK_HEADER.len() as u64;
+
+        let mut stats = PackStats::default();
+        let mut index = Vec::with_capacity(sized.len());
+        let mut depths: BTreeMap<Blob, usize> = BTreeMap::new();
+        let mut window: Vec<(Blob, Vec<u8>)> = Vec::new();
+
+        for (stored_size, id) in sized {
+            if stored_size >= STREAM_MIN {
+                let (kind, mut content) = open(id)?;
+                index.push((id, offset));
+                out.write_all(&[FULL])?;
+                //the length is only known once the object is compressed
+                let len_at = offset + 1;
+                out.write_all(&0u64.to_le_bytes())?;
+                let mut encoder = compressor(kind, &mut out)?;
+                std::io::copy(&mut content, &mut encoder)?;
+                encoder.finish()?;
+                let end = out.stream_position()?;
+                out.seek(SeekFrom::Start(len_at))?;
+                out.write_all(&(end - len_at - 8).to_le_bytes())?;
+                out.seek(SeekFrom::Start(end))?;
+                offset = end;
+                stats.objects += 1;
+                continue;
+            }
+            let (kind, object) = load(id)?;
+
+            //smallest delta against the window which saves at least half of the object
+            let mut best: Option<(Blob, Vec<u8>)> = None;
+            for (base_id, base) in &window {
+                if depths[base_id] >= MAX_DEPTH {
+                    continue;
+                }
+                let candidate = delta::encode(base, &object);
+                let limit = best.as_ref().map_or(object.len() / 2, |(_, d)| d.len());
+                if candidate.len() < limit {
+                    best = Some((*base_id, candidate));
+                }
+            }
+
+            index.push((id, offset));
+            let compressed = match &best {
+                Some((base_id, d)) => {
+                    out.write_all(&[DELTA])?;
+                    out.write_all(base_id.as_bytes())?;
+                    offset += 33;
+                    depths.insert(id, depths[base_id] + 1);
+                    stats.deltas += 1;
+                    compress(kind, d)?
+                }
+                None => {
+                    out.write_all(&[FULL])?;
+                    offset += 1;
+                    depths.insert(id, 0);
+                    compress(kind, &object)?
+                }
+            };
+            out.write_all(&(compressed.len() as u64).to_le_bytes())?;
+            out.write_all(&compressed)?;
+            offset += 8 + compressed.len() as u64;
+            stats.objects += 1;
+
+            window.push((id, object));
+            if window.len() > WINDOW {
+                window.remove(0);
+            }
+        }
+        out.into_inner().map_err(|err| err.into_error())?.commit()?;
+        stats.pack_size = offset;
+
+        index.sort();
+        let mut idx = INDEX_HEADER.to_vec();
+        idx.extend_from_slice(&(index.len() as u64).to_le_bytes());
+        for (id, offset) in &index {
+            idx.extend_from_slice(id.as_bytes());
+            idx.extend_from_slice(&offset.to_le_bytes());
+        }
+        //the pack only becomes visible once its index is in place
+        write_atomic(&index_path, &idx)?;
+        Ok((
+            Pack {
+                pack_path,
+                index_path,
+                index,
+            },
+            stats,
+        ))
+    }
+
+    pub fn same_files(&self, other: &Pack) -> bool {
+        self.index_path == other.index_path
+    }
+}
+
+fn read_u64(data: &[u8], start: usize) -> Result<u64, std::io::Error> {
+    let bytes = data
+        .get(start..start + 8)
+        .ok_or_else(|| invalid("truncated pack index"))?;
+    let mut b = [0u8; 8];
+    b.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_id(data: &[u8], start: usize) -> Result<Blob, std::io::Error> {
+    let bytes = data
+        .get(start..start + 32)
+        .ok_or_else(|| invalid("truncated pack index"))?;
+    let mut b = [0u8; 32];
+    b.copy_from_slice(bytes);
+    Ok(Blob::from_bytes(b))
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blob::Hasher, config::HashAlgorithm, objects::ObjectKind};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-pack-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_pack(dir: &Path, objects: &BTreeMap<Blob, TypedContent>) -> (Pack, PackStats) {
+        Pack::write(
+            dir,
+            objects.keys().copied().collect(),
+            |id| Ok(objects[&id].1.len() as u64),
+            |id| Ok(objects[&id].clone()),
+            |id| {
+                let (kind, content) = objects[&id].clone();
+                Ok((kind, Box::new(std::io::Cursor::new(content)) as Box<dyn Read>))
+            },
+        )
+        .unwrap()
+    }
+
+    fn object_id(content: &[u8]) -> Blob {
+        Hasher::new(HashAlgorithm::Blake3).update(content).finalize()
+    }
+
+    fn sample(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed) ^ (i >> 7) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_objects_and_deltas() {
+        let dir = temp_dir("round-trip");
+        let base = sample(20_000, 1);
+        let mut similar = base.clone();
+        similar.extend_from_slice(b"a little more");
+        let mut objects = BTreeMap::new();
+        for (kind, content) in [
+            (Some(ObjectKind::Blob), base),
+            (Some(ObjectKind::Blob), similar),
+            (Some(ObjectKind::Tree), sample(300, 7)),
+            (None, b"an object of unknown kind".to_vec()),
+        ] {
+            objects.insert(object_id(&content), (kind, content));
+        }
+        let (pack, stats) = write_pack(&dir, &objects);
+        assert_eq!(stats.objects, 4);
+        assert!(stats.deltas >= 1);
+
+        let reopened = Pack::open(&pack.index_path).unwrap();
+        for (id, object) in &objects {
+            assert_eq!(reopened.get(*id).unwrap().as_ref(), Some(object));
+        }
+        assert_eq!(reopened.get(object_id(b"not packed")).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn streams_large_objects_as_full_entries() {
+        let dir = temp_dir("large");
+        let large = sample(STREAM_MIN as usize + 1000, 2);
+        let mut similar = large.clone();
+        similar.push(0);
+        let small = sample(1000, 9);
+        let mut objects = BTreeMap::new();
+        for content in [large, similar, small] {
+            objects.insert(object_id(&content), (Some(ObjectKind::Blob), content));
+        }
+        let (pack, stats) = write_pack(&dir, &objects);
+        assert_eq!(stats.objects, 3);
+        assert_eq!(stats.deltas, 0);
+
+        let reopened = Pack::open(&pack.index_path).unwrap();
+        for (id, (kind, content)) in &objects {
+            let (read_kind, mut reader) = reopened.typed_reader(*id).unwrap().unwrap();
+            let mut streamed = Vec::new();
+            reader.read_to_end(&mut streamed).unwrap();
+            assert_eq!((read_kind, &streamed), (*kind, content));
+            assert_eq!(reopened.get(*id).unwrap(), Some((*kind, content.clone())));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_entries_longer_than_the_pack() {
+        let dir = temp_dir("corrupt-length");
+        let content = sample(1000, 3);
+        let id = object_id(&content);
+        let objects = BTreeMap::from([(id, (Some(ObjectKind::Blob), content))]);
+        let (pack, _) = write_pack(&dir, &objects);
+
+        //the length follows the entry kind of the only, full entry
+        let mut bytes = std::fs::read(&pack.pack_path).unwrap();
+        let at = pack.offset(id).unwrap() as usize + 1;
+        bytes[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&pack.pack_path, bytes).unwrap();
+
+        let err = pack.get(id).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_index_whose_count_does_not_match() {
+        let dir = temp_dir("corrupt-count");
+        let content = sample(10, 5);
+        let objects = BTreeMap::from([(object_id(&content), (None, content))]);
+        let (pack, _) = write_pack(&dir, &objects);
+
+        let mut bytes = std::fs::read(&pack.index_path).unwrap();
+        let at = INDEX_HEADER.len();
+        bytes[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&pack.index_path, bytes).unwrap();
+
+        assert!(Pack::open(&pack.index_path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
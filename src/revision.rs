@@ -0,0 +1,580 @@
+//revision expressions, naming commits on the command line
+//
+//a revision starts with HEAD, a branch name, or an object id in hex, either full
+//or a unique prefix of at least MIN_PREFIX digits, followed by any number of
+//  ~N  the Nth ancestor following first parents, `~` alone is `~1`
+//  ^N  the Nth parent, `^` alone is `^1` and `^0` the commit itself
+//a range `a..b` holds the commits reachable from b but not from a, `a...b` the
+//commits reachable from either but not both. an empty side stands for HEAD
+//
+//parents are counted in the order the commit lists them. a prefix only has to be
+//unique among the commits when a commit is needed
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    io::Read,
+    str::FromStr,
+};
+
+use serde::Serialize;
+
+use crate::{
+    blob::Blob,
+    commit::Commit,
+    dot_log::{DotLog, Error, JSON},
+    encoding,
+    objects::{ObjectKind, Objects},
+};
+
+//shorter prefixes would match most objects of any sizable repository
+pub const MIN_PREFIX: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revision {
+    Commit(Expression),
+    Range {
+        from: Expression,
+        to: Expression,
+        symmetric: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    pub base: String,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Ancestor(usize),
+    Parent(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    //not a revision expression
+    Invalid(String),
+    //no branch or object by that name
+    Unknown(String),
+    //the prefix matches several objects
+    Ambiguous(String, Vec<Blob>),
+    //the commit doesn't have that parent, or that many ancestors
+    NoParent(String),
+    //names an object that isn't a commit
+    NotACommit(String),
+    //a range where a single commit is needed
+    Range(String),
+}
+
+//commit of a history listing, see Revision::log
+#[derive(Debug, Serialize)]
+pub struct LogEntry {
+    pub id: Blob,
+    #[serde(flatten)]
+    pub commit: Commit,
+}
+
+pub fn parse(revision: &str) -> Result<Revision, Error> {
+    revision.parse().map_err(Error::Revision)
+}
+
+//names that would read as something else in a revision
+pub fn valid_branch_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "HEAD"
+        && !name.starts_with(['.', '-'])
+        && !name.contains("..")
+        && !name.contains(|c: char| c.is_whitespace() || c.is_control() || "~^:/\\".contains(c))
+}
+
+impl FromStr for Revision {
+    type Err = Problem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to, symmetric) = match s.split_once("...") {
+            Some((from, to)) => (from, to, true),
+            None => match s.split_once("..") {
+                Some((from, to)) => (from, to, false),
+                None => return Ok(Revision::Commit(s.parse()?)),
+            },
+        };
+        if from.is_empty() && to.is_empty() {
+            return Err(Problem::Invalid(s.to_string()));
+        }
+        let side = |side: &str| match side {
+            "" => "HEAD".parse(),
+            side => side.parse(),
+        };
+        Ok(Revision::Range {
+            from: side(from).map_err(|_| Problem::Invalid(s.to_string()))?,
+            to: side(to).map_err(|_| Problem::Invalid(s.to_string()))?,
+            symmetric,
+        })
+    }
+}
+
+impl FromStr for Expression {
+    type Err = Problem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Problem::Invalid(s.to_string());
+        let (base, mut rest) = s.split_at(s.find(['~', '^']).unwrap_or(s.len()));
+        if base.is_empty() || base.contains("..") || base.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        let mut steps = Vec::new();
+        while let Some(operator) = rest.chars().next() {
+            rest = &rest[operator.len_utf8()..];
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let count = match &rest[..digits] {
+                "" => 1,
+                number => number.parse().map_err(|_| invalid())?,
+            };
+            rest = &rest[digits..];
+            steps.push(match operator {
+                '~' => Step::Ancestor(count),
+                '^' => Step::Parent(count),
+                _ => return Err(invalid()),
+            });
+        }
+        Ok(Expression {
+            base: base.to_string(),
+            steps,
+        })
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.base)?;
+        for step in &self.steps {
+            match step {
+                Step::Ancestor(count) => write!(f, "~{}", count)?,
+                Step::Parent(count) => write!(f, "^{}", count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Revision::Commit(expression) => write!(f, "{}", expression),
+            Revision::Range {
+                from,
+                to,
+                symmetric,
+            } => write!(f, "{}{}{}", from, if *symmetric { "..." } else { ".." }, to),
+        }
+    }
+}
+
+impl Expression {
+    //the object named, which is only known to be a commit when there are steps
+    pub fn resolve<O: Objects<Error = std::io::Error> + ?Sized>(
+        &self,
+        dot_log: &DotLog,
+        objects: &O,
+    ) -> Result<Blob, Error> {
+        self.resolve_as(dot_log, objects, false)
+    }
+
+    fn resolve_as<O: Objects<Error = std::io::Error> + ?Sized>(
+        &self,
+        dot_log: &DotLog,
+        objects: &O,
+        commit: bool,
+    ) -> Result<Blob, Error> {
+        let commit = commit || !self.steps.is_empty();
+        let mut id = resolve_base(self, dot_log, objects, commit)?;
+        for step in &self.steps {
+            let no_parent = || Error::Revision(Problem::NoParent(self.to_string()));
+            id = match *step {
+                Step::Parent(0) => {
+                    read_commit(objects, id, self)?;
+                    id
+                }
+                Step::Parent(n) => read_commit(objects, id, self)?
+                    .previous
+                    .into_iter()
+                    .nth(n - 1)
+                    .ok_or_else(no_parent)?,
+                Step::Ancestor(n) => {
+                    for _ in 0..n {
+                        id = read_commit(objects, id, self)?
+                            .previous
+                            .into_iter()
+                            .next()
+                            .ok_or_else(no_parent)?;
+                    }
+                    id
+                }
+            };
+        }
+        Ok(id)
+    }
+
+    //the commit named
+    pub fn resolve_commit<O: Objects<Error = std::io::Error> + ?Sized>(
+        &self,
+        dot_log: &DotLog,
+        objects: &O,
+    ) -> Result<Blob, Error> {
+        let id = self.resolve_as(dot_log, objects, true)?;
+        read_commit(objects, id, self)?;
+        Ok(id)
+    }
+}
+
+impl Revision {
+    //the commit named, ranges don't name one
+    pub fn resolve<O: Objects<Error = std::io::Error> + ?Sized>(
+        &self,
+        dot_log: &DotLog,
+        objects: &O,
+    ) -> Result<Blob, Error> {
+        match self {
+            Revision::Commit(expression) => expression.resolve_commit(dot_log, objects),
+            Revision::Range { .. } => Err(Error::Revision(Problem::Range(self.to_string()))),
+        }
+    }
+
+    //the commits named, a single commit stands for its whole history
+    //children come before their parents
+    pub fn log<O: Objects<Error = std::io::Error> + ?Sized>(
+        &self,
+        dot_log: &DotLog,
+        objects: &O,
+    ) -> Result<Vec<LogEntry>, Error> {
+        let ids = |entries: &[LogEntry]| -> BTreeSet<Blob> {
+            entries.iter().map(|entry| entry.id).collect()
+        };
+        let (included, excluded) = match self {
+            Revision::Commit(expression) => (
+                history(objects, &[expression.resolve_commit(dot_log, objects)?])?,
+                BTreeSet::new(),
+            ),
+            Revision::Range {
+                from,
+                to,
+                symmetric,
+            } => {
+                let from = from.resolve_commit(dot_log, objects)?;
+                let to = to.resolve_commit(dot_log, objects)?;
+                let from_history = ids(&history(objects, &[from])?);
+                if *symmetric {
+                    let to_history = ids(&history(objects, &[to])?);
+                    let common = from_history.intersection(&to_history).copied().collect();
+                    (history(objects, &[to, from])?, common)
+                } else {
+                    (history(objects, &[to])?, from_history)
+                }
+            }
+        };
+        Ok(included
+            .into_iter()
+            .filter(|entry| !excluded.contains(&entry.id))
+            .collect())
+    }
+}
+
+//the commits reachable from `tips`, children before their parents
+fn history<O: Objects<Error = std::io::Error> + ?Sized>(
+    objects: &O,
+    tips: &[Blob],
+) -> Result<Vec<LogEntry>, Error> {
+    //depth first, a commit is finished once all its parents are
+    let mut seen = BTreeSet::new();
+    let mut finished = Vec::new();
+    let mut stack: Vec<(Blob, Option<Commit>)> = tips.iter().rev().map(|id| (*id, None)).collect();
+    while let Some((id, commit)) = stack.pop() {
+        match commit {
+            Some(commit) => finished.push(LogEntry { id, commit }),
+            None => {
+                if !seen.insert(id) {
+                    continue;
+                }
+                let commit: Commit = objects.read_json(id)?;
+                let parents: Vec<Blob> = commit.previous.iter().rev().copied().collect();
+                stack.push((id, Some(commit)));
+                stack.extend(parents.into_iter().map(|parent| (parent, None)));
+            }
+        }
+    }
+    finished.reverse();
+    Ok(finished)
+}
+
+fn read_commit<O: Objects<Error = std::io::Error> + ?Sized>(
+    objects: &O,
+    id: Blob,
+    expression: &Expression,
+) -> Result<Commit, Error> {
+    match objects.read_json(id) {
+        Err(Error::WrongKind { .. }) | Err(Error::Serde(_)) => {
+            Err(Error::Revision(Problem::NotACommit(expression.to_string())))
+        }
+        result => result,
+    }
+}
+
+//only objects stored as commits are decoded, and those of unknown kind, written
+//before kinds were recorded, that start like a json or binary commit
+fn is_commit<O: Objects<Error = std::io::Error> + ?Sized>(objects: &O, id: Blob) -> Result<bool, Error> {
+    let decode = match objects.kind(id)? {
+        Some(Some(kind)) => kind == ObjectKind::Commit,
+        Some(None) => {
+            let mut start = Vec::new();
+            if let Some(reader) = objects.get_reader(id)? {
+                reader.take(4).read_to_end(&mut start)?;
+            }
+            start.starts_with(b"{") || encoding::is_binary(&start)
+        }
+        None => false,
+    };
+    if !decode {
+        return Ok(false);
+    }
+    match objects.read_json::<Commit>(id) {
+        Ok(_) => Ok(true),
+        Err(Error::WrongKind { .. }) | Err(Error::Serde(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+//with `commit`, a prefix is matched against the commits only
+fn resolve_base<O: Objects<Error = std::io::Error> + ?Sized>(
+    expression: &Expression,
+    dot_log: &DotLog,
+    objects: &O,
+    commit: bool,
+) -> Result<Blob, Error> {
+    let base = expression.base.as_str();
+    if base == "HEAD" {
+        return dot_log.get_branch_commit_hash(&dot_log.get_branch()?);
+    }
+    if dot_log.branches()?.iter().any(|branch| branch == base) {
+        return dot_log.get_branch_commit_hash(base);
+    }
+    let unknown = || Error::Revision(Problem::Unknown(base.to_string()));
+    if let Ok(id) = base.parse::<Blob>() {
+        return match objects.exists(id)? {
+            true => Ok(id),
+            false => Err(unknown()),
+        };
+    }
+    if base.len() < MIN_PREFIX || !base.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(unknown());
+    }
+    let prefix = base.to_ascii_lowercase();
+    let mut matches: Vec<Blob> = objects
+        .ids()?
+        .into_iter()
+        .filter(|id| id.to_string().starts_with(&prefix))
+        .collect();
+    if commit && !matches.is_empty() {
+        let mut commits = Vec::new();
+        for id in matches {
+            if is_commit(objects, id)? {
+                commits.push(id);
+            }
+        }
+        if commits.is_empty() {
+            return Err(Error::Revision(Problem::NotACommit(expression.to_string())));
+        }
+        matches = commits;
+    }
+    match matches.len() {
+        0 => Err(unknown()),
+        1 => Ok(matches[0]),
+        _ => Err(Error::Revision(Problem::Ambiguous(base.to_string(), matches))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Config, ObjectEncoding},
+        directory::Directory,
+        objects::{cache::CachedObjects, BoxedObjects},
+    };
+
+    fn expression(s: &str) -> Expression {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_steps() {
+        assert_eq!(
+            expression("main~2^2^~"),
+            Expression {
+                base: "main".to_string(),
+                steps: vec![
+                    Step::Ancestor(2),
+                    Step::Parent(2),
+                    Step::Parent(1),
+                    Step::Ancestor(1),
+                ],
+            }
+        );
+        assert_eq!(expression("HEAD^0").steps, vec![Step::Parent(0)]);
+        assert_eq!(expression("main~2^2").to_string(), "main~2^2");
+        for invalid in ["", "~1", "a b", "main~x", "main^99999999999999999999"] {
+            assert!(matches!(
+                invalid.parse::<Expression>(),
+                Err(Problem::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(
+            "a..b".parse(),
+            Ok(Revision::Range {
+                from: expression("a"),
+                to: expression("b"),
+                symmetric: false,
+            })
+        );
+        assert_eq!(
+            "...b~1".parse(),
+            Ok(Revision::Range {
+                from: expression("HEAD"),
+                to: expression("b~1"),
+                symmetric: true,
+            })
+        );
+        assert_eq!("a..".parse::<Revision>().unwrap().to_string(), "a..HEAD");
+        assert!(matches!("..".parse::<Revision>(), Err(Problem::Invalid(_))));
+        assert!(matches!("a..b..c".parse::<Revision>(), Err(Problem::Invalid(_))));
+    }
+
+    #[test]
+    fn refuses_branch_names_that_read_as_revisions() {
+        assert!(valid_branch_name("feature"));
+        for name in ["", "HEAD", "a..b", "a~1", "a^", "-a", ".a", "a b", "a/b"] {
+            assert!(!valid_branch_name(name), "{}", name);
+        }
+    }
+
+    //root, then a and b on master, c beside them, and m merging b and c
+    struct History {
+        dot_log: DotLog,
+        objects: CachedObjects<BoxedObjects>,
+        root: Blob,
+        a: Blob,
+        b: Blob,
+        c: Blob,
+        m: Blob,
+        dir: std::path::PathBuf,
+    }
+
+    fn history(name: &str) -> History {
+        let dir = std::env::temp_dir().join(format!("log-revision-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dot_log = DotLog::init(dir.join(".log"), &Config::default()).unwrap();
+        let objects = dot_log.get_objects().unwrap();
+        let root = dot_log.get_branch_commit_hash("master").unwrap();
+        let tree = objects
+            .insert_encoded(&Directory::default(), ObjectEncoding::Binary)
+            .unwrap();
+        let commit = |message: &str, previous: Vec<Blob>| {
+            let commit = Commit {
+                message: message.to_string(),
+                directory: tree,
+                previous,
+            };
+            objects.insert_encoded(&commit, ObjectEncoding::Binary).unwrap()
+        };
+        let a = commit("a", vec![root]);
+        let b = commit("b", vec![a]);
+        let c = commit("c", vec![root]);
+        let m = commit("m", vec![b, c]);
+        dot_log.set_branch_commit_hash("master", m).unwrap();
+        dot_log.set_branch_commit_hash("side", c).unwrap();
+        History {
+            dot_log,
+            objects,
+            root,
+            a,
+            b,
+            c,
+            m,
+            dir,
+        }
+    }
+
+    impl History {
+        fn resolve(&self, revision: &str) -> Result<Blob, Error> {
+            parse(revision)?.resolve(&self.dot_log, &self.objects)
+        }
+
+        fn log(&self, revision: &str) -> Vec<Blob> {
+            parse(revision)
+                .unwrap()
+                .log(&self.dot_log, &self.objects)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.id)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn resolves_steps() {
+        let h = history("steps");
+        assert_eq!(h.resolve("HEAD").unwrap(), h.m);
+        assert_eq!(h.resolve("master~1").unwrap(), h.b);
+        assert_eq!(h.resolve("master~3").unwrap(), h.root);
+        assert_eq!(h.resolve("HEAD^2").unwrap(), h.c);
+        assert_eq!(h.resolve("HEAD^2~1").unwrap(), h.root);
+        assert_eq!(h.resolve("side^0").unwrap(), h.c);
+        assert_eq!(h.resolve(&h.a.to_string()).unwrap(), h.a);
+        assert!(matches!(
+            h.resolve("HEAD^3"),
+            Err(Error::Revision(Problem::NoParent(_)))
+        ));
+        assert!(matches!(
+            h.resolve("master~4"),
+            Err(Error::Revision(Problem::NoParent(_)))
+        ));
+        assert!(matches!(
+            h.resolve("nothing"),
+            Err(Error::Revision(Problem::Unknown(_)))
+        ));
+        std::fs::remove_dir_all(&h.dir).unwrap();
+    }
+
+    #[test]
+    fn only_commits_resolve_as_commits() {
+        let h = history("kinds");
+        let tree = h.objects.read_json::<Commit>(h.a).unwrap().directory;
+        assert!(is_commit(&h.objects, h.a).unwrap());
+        assert!(!is_commit(&h.objects, tree).unwrap());
+        assert!(matches!(
+            h.resolve(&tree.to_string()),
+            Err(Error::Revision(Problem::NotACommit(_)))
+        ));
+        assert!(matches!(
+            h.resolve("HEAD..side"),
+            Err(Error::Revision(Problem::Range(_)))
+        ));
+        std::fs::remove_dir_all(&h.dir).unwrap();
+    }
+
+    #[test]
+    fn logs_ranges() {
+        let h = history("ranges");
+        assert_eq!(h.log("HEAD"), vec![h.m, h.c, h.b, h.a, h.root]);
+        assert_eq!(h.log("side..master"), vec![h.m, h.b, h.a]);
+        assert_eq!(h.log("master..side"), vec![]);
+        assert_eq!(h.log("master~1...side"), vec![h.b, h.a, h.c]);
+        assert_eq!(h.log("..side"), vec![]);
+        std::fs::remove_dir_all(&h.dir).unwrap();
+    }
+}